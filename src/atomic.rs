@@ -0,0 +1,116 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+// Replaces a file without ever leaving it half written: the contents go to a
+// temporary file in the same directory, which is synced to disk and renamed
+// over the original. The original's permissions are kept, a symlink keeps
+// pointing to the replaced file, and `backup` keeps the previous contents as
+// `<name>~`. A write-protected file is left alone, as the rename would
+// replace it anyway. Errors say which step failed.
+pub fn write_atomically<T, F>(file_name: &str, backup: bool, write: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<T, Error>,
+{
+    let path = fs::canonicalize(file_name).unwrap_or_else(|_| PathBuf::from(file_name));
+    let original = fs::metadata(&path).ok();
+    if original.as_ref().map_or(false, |metadata| metadata.permissions().readonly()) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Could not replace {}: file is read-only", path.display()),
+        ));
+    }
+    let temporary = sibling(&path, ".", ".hecto-save");
+    let result = write_temporary(&temporary, write).and_then(|value| {
+        if let Some(metadata) = &original {
+            fs::set_permissions(&temporary, metadata.permissions())
+                .map_err(|error| context("Could not copy permissions", &path, &error))?;
+            if backup {
+                let backup = sibling(&path, "", "~");
+                // The copy takes the mode of the original, so an old backup
+                // may not be writable.
+                fs::remove_file(&backup).ok();
+                fs::copy(&path, &backup)
+                    .map_err(|error| context("Could not write backup", &backup, &error))?;
+            }
+        }
+        fs::rename(&temporary, &path)
+            .map_err(|error| context("Could not replace", &path, &error))?;
+        Ok(value)
+    });
+    if result.is_err() {
+        fs::remove_file(&temporary).ok();
+    } else if let Some(directory) = path.parent() {
+        // Makes the rename itself durable; not every platform can open a
+        // directory, so this is best effort.
+        if let Ok(directory) = File::open(directory) {
+            directory.sync_all().ok();
+        }
+    }
+    result
+}
+
+fn write_temporary<T, F>(temporary: &Path, write: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<T, Error>,
+{
+    let file = File::create(temporary)
+        .map_err(|error| context("Could not create", temporary, &error))?;
+    let mut writer = BufWriter::new(file);
+    let value = write(&mut writer)
+        .and_then(|value| writer.flush().map(|_| value))
+        .map_err(|error| context("Could not write", temporary, &error))?;
+    writer
+        .get_ref()
+        .sync_all()
+        .map_err(|error| context("Could not sync", temporary, &error))?;
+    Ok(value)
+}
+
+fn sibling(path: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().to_string());
+    path.with_file_name(format!("{}{}{}", prefix, name, suffix))
+}
+
+fn context(step: &str, path: &Path, error: &Error) -> Error {
+    Error::new(error.kind(), format!("{} {}: {}", step, path.display(), error))
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_write_atomically() {
+        let path = std::env::temp_dir().join(format!("hecto-atomic-{}.txt", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        fs::write(&path, "old").unwrap();
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions.clone()).unwrap();
+        let refused = write_atomically(&file_name, true, |writer| writer.write_all(b"new"));
+        assert_eq!(refused.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        let backup = sibling(&path, "", "~");
+        fs::write(&backup, "older").unwrap();
+        fs::set_permissions(&backup, permissions.clone()).unwrap();
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions.clone()).unwrap();
+        write_atomically(&file_name, true, |writer| writer.write_all(b"new")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::metadata(&path).unwrap().permissions(), permissions);
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "old");
+
+        let failed: Result<(), Error> = write_atomically(&file_name, false, |writer| {
+            writer.write_all(b"partial")?;
+            Err(Error::new(std::io::ErrorKind::Other, "disk full"))
+        });
+        assert!(failed.unwrap_err().to_string().contains("disk full"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!sibling(&path, ".", ".hecto-save").exists());
+        fs::remove_file(backup).ok();
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs;
+use std::path::{self, Path, MAIN_SEPARATOR};
+
+// The paths that `input` can be completed to, sorted, with a separator after
+// directories. Hidden entries are only offered once a `.` has been typed.
+// Either separator ends a directory where the platform accepts both.
+pub fn complete_path(input: &str) -> Vec<String> {
+    let (directory, prefix) = match input.rfind(path::is_separator) {
+        Some(index) => input.split_at(index.saturating_add(1)),
+        None => ("", input),
+    };
+    let entries = match fs::read_dir(if directory.is_empty() { "." } else { directory }) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let mut candidate = format!("{}{}", directory, name);
+            if Path::new(&candidate).is_dir() {
+                candidate.push(MAIN_SEPARATOR);
+            }
+            Some(candidate)
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+// The longest start shared by all `candidates`, in whole characters.
+pub fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix = match candidates.first() {
+        Some(first) => first.as_str(),
+        None => return String::new(),
+    };
+    for candidate in candidates {
+        let len = prefix
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or_else(|| prefix.len().min(candidate.len()), |((index, _), _)| index);
+        prefix = prefix.get(..len).unwrap_or_default();
+    }
+    prefix.to_string()
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_complete_path() {
+        let directory = std::env::temp_dir().join(format!("hecto-complete-{}", std::process::id()));
+        fs::create_dir_all(directory.join("文档")).unwrap();
+        fs::write(directory.join("文件.txt"), "").unwrap();
+        fs::write(directory.join("notes.txt"), "").unwrap();
+        fs::write(directory.join(".hidden"), "").unwrap();
+        let base = format!("{}{}", directory.to_string_lossy(), MAIN_SEPARATOR);
+        let names = |input: &str| -> Vec<String> {
+            complete_path(&format!("{}{}", base, input))
+                .into_iter()
+                .map(|candidate| candidate.get(base.len()..).unwrap_or_default().to_string())
+                .collect()
+        };
+        let subdirectory = format!("文档{}", MAIN_SEPARATOR);
+        assert_eq!(names("文"), vec!["文件.txt", subdirectory.as_str()]);
+        assert_eq!(names(""), vec!["notes.txt", "文件.txt", subdirectory.as_str()]);
+        assert_eq!(names("."), vec![".hidden"]);
+        assert!(names("missing").is_empty());
+        assert_eq!(common_prefix(&names("文")), "文");
+        assert_eq!(common_prefix(&["abc".to_string(), "abd".to_string()]), "ab");
+        assert_eq!(common_prefix(&["ab".to_string(), "abc".to_string()]), "ab");
+        fs::remove_dir_all(directory).ok();
+    }
+}
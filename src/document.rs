@@ -1,50 +1,180 @@
+use crate::atomic::write_atomically;
+use crate::history::{Branch, Change, History, Operation, Step};
+use crate::line_ending::{self, split_lines};
+use crate::rope::Rope;
 use crate::FileType;
+use crate::LineEnding;
 use crate::Position;
 use crate::Row;
 use crate::SearchDirection;
+use crate::SearchQuery;
+use crate::TextEncoding;
+use crate::stream::TextStream;
+use crate::width;
+use std::cmp;
 use std::fs;
-use std::io::{Error, Write};
-use std::time::{Duration, Instant};
+use std::mem;
+use std::ops::Range;
+use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use unicode_segmentation::UnicodeSegmentation;
 
-#[derive(Default)]
-pub struct EditorState {
-    rows: Vec<Row>,
-    cursor_position: Position,
+const LARGE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+// How much of a large file is sampled to pick its encoding and line ending.
+const LARGE_FILE_SAMPLE: u64 = 64 * 1024;
+// How many rows a search reads at first, and how many a match may span.
+const SEARCH_WINDOW: usize = 64;
+
+// What the file looked like when it was last opened or saved.
+#[derive(PartialEq, Clone, Copy)]
+struct DiskState {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl DiskState {
+    fn read(file_name: &str) -> Option<Self> {
+        let metadata = fs::metadata(file_name).ok()?;
+        Some(Self {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        })
+    }
 }
 
 pub struct Document {
-    rows: Vec<Row>,
+    rows: Rope,
     pub file_name: Option<String>,
     dirty: bool,
     file_type: FileType,
-    history: Vec<(Vec<Row>, Position)>,
-    history_index: usize,
-    last_edit_time: Option<Instant>,
-    batch_duration: Duration,
+    history: History,
+    revision: usize,
+    persistent_undo: bool,
+    content_hash: u64,
+    highlighted_until: usize,
+    // Used for new lines and shown in the status bar; rows read from the file
+    // keep their own endings.
+    line_ending: LineEnding,
+    mixed_endings: bool,
+    final_newline: bool,
+    encoding: TextEncoding,
+    backup: bool,
+    disk_state: Option<DiskState>,
+    readonly: bool,
+    // Where the last query counted matched, and at which revision, so that
+    // redrawing or moving between matches does not search the document again.
+    matches: Option<(usize, SearchQuery, Vec<Position>)>,
 }
 
 impl Document {
     pub fn open(filename: &str) -> Result<Self, std::io::Error> {
-        let contents = fs::read_to_string(filename)?;
-        let file_type = FileType::from(filename);
-        let mut rows = Vec::new();
-        for value in contents.lines() {
-            rows.push(Row::from(value));
+        Self::open_with_encoding(filename, None)
+    }
+    // Detects the encoding unless one is given.
+    pub fn open_with_encoding(
+        filename: &str,
+        encoding: Option<TextEncoding>,
+    ) -> Result<Self, std::io::Error> {
+        if fs::metadata(filename)?.len() > LARGE_FILE_SIZE {
+            let mut sample = Vec::new();
+            fs::File::open(filename)?
+                .take(LARGE_FILE_SAMPLE)
+                .read_to_end(&mut sample)?;
+            // Only whole lines, so the sample does not end inside a character.
+            if let Some(end) = sample.iter().rposition(|byte| *byte == b'\n') {
+                sample.truncate(end);
+            }
+            let encoding = encoding.unwrap_or_else(|| TextEncoding::detect(&sample));
+            if encoding.is_ascii_compatible() {
+                return Self::open_mapped(filename, &sample, encoding);
+            }
         }
+        let disk_state = DiskState::read(filename);
+        let bytes = fs::read(filename)?;
         Ok(Self {
+            file_name: Some(filename.to_string()),
+            file_type: FileType::from(filename),
+            disk_state,
+            ..Self::from_bytes(&bytes, encoding)
+        })
+    }
+    // An unnamed document holding `bytes`, such as text piped to standard
+    // input.
+    pub fn from_bytes(bytes: &[u8], encoding: Option<TextEncoding>) -> Self {
+        let encoding = encoding.unwrap_or_else(|| TextEncoding::detect(bytes));
+        let contents = encoding.decode(bytes.strip_prefix(encoding.bom()).unwrap_or(bytes));
+        let lines = split_lines(&contents, true);
+        let (line_ending, mixed_endings) =
+            line_ending::detect(lines.iter().filter_map(|(_, ending)| *ending));
+        let final_newline = lines.last().map_or(true, |(_, ending)| ending.is_some());
+        let mut rows = Vec::new();
+        for (value, ending) in lines {
+            rows.push(Row::with_ending(value, ending));
+        }
+        let mut rows = Rope::from(rows);
+        rows.set_encoding(encoding);
+        Self {
             rows,
+            content_hash: content_hash(bytes),
+            line_ending,
+            mixed_endings,
+            final_newline,
+            encoding,
+            ..Self::default()
+        }
+    }
+    // An unnamed document made of `rows` as they are, for text that is not
+    // read from a file.
+    pub fn from_rows(rows: Vec<Row>) -> Self {
+        Self {
+            rows: Rope::from(rows),
+            ..Self::default()
+        }
+    }
+    // Large files are indexed in the background and only read where they are
+    // viewed or edited; see `Rope::map`.
+    fn open_mapped(
+        filename: &str,
+        sample: &[u8],
+        encoding: TextEncoding,
+    ) -> Result<Self, std::io::Error> {
+        let mut file = fs::File::open(filename)?;
+        let mut last_byte = [b'\n'];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last_byte)?;
+        let (line_ending, mixed_endings) = line_ending::detect(
+            split_lines(&encoding.decode(sample), false)
+                .into_iter()
+                .filter_map(|(_, ending)| ending),
+        );
+        Ok(Self {
+            rows: Rope::map(filename, encoding)?,
             file_name: Some(filename.to_string()),
-            dirty: false,
-            file_type,
-            history: vec![(Vec::new(), Position::default())],
-            history_index: 0,
-            last_edit_time: None,
-            batch_duration: Duration::from_millis(1000),
+            file_type: FileType::from(filename),
+            line_ending,
+            mixed_endings,
+            final_newline: last_byte == [b'\n'],
+            encoding,
+            disk_state: DiskState::read(filename),
+            ..Self::default()
         })
     }
+    pub fn is_indexing(&self) -> bool {
+        self.rows.is_indexing()
+    }
+    pub fn poll_index(&mut self) -> bool {
+        self.rows.poll_index()
+    }
+    pub fn load_rows(&mut self, start: usize, end: usize) -> Result<(), Error> {
+        self.rows.load(start, end)
+    }
     pub fn file_type(&self) -> String {
         self.file_type.name()
     }
+    pub fn expand_tab(&self) -> bool {
+        self.file_type.expand_tab()
+    }
     pub fn row(&self, index: usize) -> Option<&Row> {
         self.rows.get(index)
     }
@@ -59,185 +189,1322 @@ impl Document {
             return;
         }
         if at.y == self.rows.len() {
-            self.rows.push(Row::default());
+            self.edit(Operation::InsertRow { y: at.y });
             return;
         }
-        #[allow(clippy::indexing_slicing)]
-        let current_row = &mut self.rows[at.y];
-        let new_row = current_row.split(at.x);
-        #[allow(clippy::integer_arithmetic)]
-        self.rows.insert(at.y + 1, new_row);
+        let x = cmp::min(at.x, self.rows.get(at.y).map_or(0, Row::len));
+        self.edit(Operation::Split {
+            at: Position { x, y: at.y },
+            ending: self.ending_at(at.y),
+        });
     }
     pub fn insert(&mut self, at: &Position, c: char) {
-        self.save_state(&Position::default());
-        if at.y > self.rows.len() {
+        // A large file that is still being indexed has more rows to come
+        // after the last one so far, so nothing can be added there yet.
+        if at.y > self.rows.len() || (at.y == self.rows.len() && self.rows.is_indexing()) {
             return;
         }
+        self.load_around(at.y);
         self.dirty = true;
         if c == '\n' {
             self.insert_newline(at);
         } else if at.y == self.rows.len() {
-            let mut row = Row::default();
-            row.insert(0, c);
-            self.rows.push(row);
+            self.edit(Operation::InsertRow { y: at.y });
+            self.insert_char(&Position { x: 0, y: at.y }, c);
         } else {
-            #[allow(clippy::indexing_slicing)]
-            let row = &mut self.rows[at.y];
-            row.insert(at.x, c);
+            self.insert_char(at, c);
         }
         self.unhighlight_rows(at.y);
     }
+    fn insert_char(&mut self, at: &Position, c: char) {
+        let mut buffer = [0; 4];
+        self.insert_str_at(at, c.encode_utf8(&mut buffer));
+    }
+    fn insert_str_at(&mut self, at: &Position, text: &str) {
+        self.splice_at(at, 0, text);
+    }
+    fn remove_at(&mut self, at: &Position, count: usize) {
+        self.splice_at(at, count, "");
+    }
+    // Puts `text` in place of `count` graphemes at `at` and records the run
+    // of graphemes that changed. An edit can make the graphemes around it
+    // join or come apart, as a combining mark does with the letter before it
+    // or a regional indicator with its neighbours, and recording just the
+    // characters typed or deleted would put later positions in the history
+    // off a boundary.
+    fn splice_at(&mut self, at: &Position, count: usize, text: &str) {
+        self.load_around(at.y);
+        let changed = self.rows.edit(at.y).map(|row| row.splice(at.x, count, text));
+        if let Some((x, old, new)) = changed {
+            if let Some(operation) = replacement(Position { x, y: at.y }, old, new) {
+                self.record(operation);
+            }
+        }
+    }
+    // Records the change of row `y` to `edited` as the run of graphemes that
+    // differ, for edits that are not one splice.
+    #[allow(clippy::integer_arithmetic)]
+    fn replace_row(&mut self, y: usize, edited: &Row) {
+        let (start, removed, added) = match self.rows.get(y) {
+            Some(row) => {
+                let prefix = row.common_prefix(edited);
+                let suffix = row
+                    .common_suffix(edited)
+                    .min(cmp::min(row.len(), edited.len()) - prefix);
+                (
+                    prefix,
+                    row.graphemes(prefix, row.len() - prefix - suffix),
+                    edited.graphemes(prefix, edited.len() - prefix - suffix),
+                )
+            }
+            None => return,
+        };
+        if let Some(operation) = replacement(Position { x: start, y }, removed, added) {
+            self.edit(operation);
+        }
+    }
+    // Joins row `y` and the next one. Where graphemes at the seam join, the
+    // ones that change are taken off the next row and replaced on this one
+    // first, so that the join itself only puts rows together unchanged.
+    #[allow(clippy::integer_arithmetic)]
+    fn join_rows(&mut self, y: usize) {
+        let next = y.saturating_add(1);
+        let (row, next_row) = match (self.rows.get(y), self.rows.get(next)) {
+            (Some(row), Some(next_row)) => (row.clone(), next_row.clone()),
+            _ => return,
+        };
+        let mut joined = row.clone();
+        joined.append(&next_row);
+        let prefix = row.common_prefix(&joined);
+        let suffix = next_row.common_suffix(&joined).min(joined.len() - prefix);
+        let tail = row.graphemes(prefix, row.len() - prefix);
+        let head = next_row.graphemes(0, next_row.len() - suffix);
+        let middle_len = joined.len() - prefix - suffix;
+        let middle = joined.graphemes(prefix, middle_len);
+        if !head.is_empty() {
+            self.edit(Operation::Delete {
+                at: Position { x: 0, y: next },
+                text: head,
+            });
+        }
+        if let Some(operation) = replacement(Position { x: prefix, y }, tail, middle) {
+            self.edit(operation);
+        }
+        self.edit(Operation::Join {
+            at: Position {
+                x: prefix + middle_len,
+                y,
+            },
+            ending: row.ending(),
+        });
+    }
 
+    // Only rows below `highlighted_until` can have been highlighted, which
+    // keeps an edit from touching every row after it in a large file.
     fn unhighlight_rows(&mut self, start: usize) {
         let start = start.saturating_sub(1);
-        for row in self.rows.iter_mut().skip(start) {
-            row.is_highlighted = false;
+        for y in start..self.highlighted_until {
+            if let Some(row) = self.rows.get_mut(y) {
+                row.is_highlighted = false;
+            }
         }
+        self.highlighted_until = cmp::min(self.highlighted_until, start);
     }
-    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    #[allow(clippy::integer_arithmetic)]
     pub fn delete(&mut self, at: &Position) -> bool {
-        self.save_state(&Position::default());
+        self.load_around(at.y);
         let len = self.rows.len();
-        if at.y >= len {
+        let row = if let Some(row) = self.rows.get(at.y) {
+            row
+        } else {
             return false;
-        }
+        };
         self.dirty = true;
-        if at.x == self.rows[at.y].len() && at.y + 1 < len {
-            let next_row = self.rows.remove(at.y + 1);
-            let row = &mut self.rows[at.y];
-            row.append(&next_row);
-        } else {
-            let row = &mut self.rows[at.y];
-            row.delete(at.x);
+        if at.x == row.len() && at.y + 1 < len {
+            self.join_rows(at.y);
+        } else if at.x < row.len() {
+            self.remove_at(at, 1);
         }
         self.unhighlight_rows(at.y);
         true
     }
     pub fn save(&mut self) -> Result<(), Error> {
+        if self.readonly {
+            return Err(Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "The file is open read-only",
+            ));
+        }
         if let Some(file_name) = &self.file_name {
-            let mut file = fs::File::create(file_name)?;
             self.file_type = FileType::from(file_name);
-            for row in &mut self.rows {
-                file.write_all(row.as_bytes())?;
-                file.write_all(b"\n")?;
+            if self.rows.is_mapped() {
+                self.content_hash = self.rows.save_mapped(
+                    file_name,
+                    self.line_ending,
+                    self.final_newline,
+                    self.backup,
+                )?;
+                self.disk_state = DiskState::read(file_name);
+                self.dirty = false;
+                return Ok(());
             }
+            let mut contents = Vec::new();
+            self.write_to(&mut contents)?;
+            write_atomically(file_name, self.backup, |writer| writer.write_all(&contents))?;
+            self.remove_swap_file();
+            self.disk_state = DiskState::read(file_name);
+            self.content_hash = content_hash(&contents);
             self.dirty = false;
+            if self.persistent_undo {
+                // The undo file is a convenience; failing to write it must not
+                // turn a successful save into an error.
+                self.write_undo_file().ok();
+            }
         }
         Ok(())
     }
-    pub fn is_dirty(&self) -> bool {
-        self.dirty
+    // Saves under a new name, which the document keeps from then on; on
+    // failure it stays as it was.
+    pub fn save_as(&mut self, file_name: String) -> Result<(), Error> {
+        let file_type = FileType::from(file_name.as_str());
+        let old_swap_file_name = self.swap_file_name();
+        let old_name = self.file_name.replace(file_name);
+        let old_type = mem::replace(&mut self.file_type, file_type);
+        let old_disk_state = self.disk_state.take();
+        if let Err(error) = self.save() {
+            self.file_name = old_name;
+            self.file_type = old_type;
+            self.disk_state = old_disk_state;
+            return Err(error);
+        }
+        if let Some(swap_file_name) = old_swap_file_name {
+            fs::remove_file(swap_file_name).ok();
+        }
+        self.unhighlight_rows(0);
+        Ok(())
+    }
+    // Writes the contents as they would be saved.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        self.rows
+            .write_to(writer, self.line_ending, self.final_newline)
+    }
+    pub fn line_ending(&self) -> String {
+        if self.mixed_endings {
+            "Mixed".to_string()
+        } else {
+            self.line_ending.name().to_string()
+        }
+    }
+    // Converts every line to `ending`, as one undo step; a missing final
+    // newline stays missing. Mapped documents are copied from the file chunk
+    // by chunk, so they cannot be converted.
+    pub fn set_line_ending(&mut self, ending: LineEnding) -> bool {
+        if self.rows.is_mapped() {
+            return false;
+        }
+        let mut reader = self.rows.reader();
+        let rows = (0..self.rows.len())
+            .filter_map(|y| {
+                let from = reader.get(y)?.ending()?;
+                Some((y, Some(from), None))
+            })
+            .collect();
+        self.begin_change();
+        self.edit(Operation::SetEndings {
+            from: self.line_ending,
+            to: ending,
+            rows,
+        });
+        self.end_change();
+        self.dirty = true;
+        true
+    }
+    pub fn encoding(&self) -> String {
+        self.encoding.name()
+    }
+    // Changes the encoding the document is saved in. Mapped documents copy
+    // most of their bytes straight from the file, so they keep theirs.
+    pub fn set_encoding(&mut self, encoding: TextEncoding) -> bool {
+        if self.rows.is_mapped() {
+            return false;
+        }
+        self.encoding = encoding;
+        self.rows.set_encoding(encoding);
+        self.dirty = true;
+        true
     }
-    #[allow(clippy::indexing_slicing)]
-    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
-        if at.y >= self.rows.len() {
+    // Reads the file again, detecting its encoding unless one is given.
+    pub fn reload(&mut self, encoding: Option<TextEncoding>) -> Result<(), Error> {
+        if let Some(file_name) = &self.file_name {
+            let mut document = Self::open_with_encoding(file_name, encoding)?;
+            document.set_persistent_undo(self.persistent_undo);
+            document.set_backup(self.backup);
+            document.readonly = self.readonly;
+            *self = document;
+        }
+        Ok(())
+    }
+    // Read-only documents are never saved; the editor refuses to change them.
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+    // Keeps the previous contents as `<name>~` on every save.
+    pub fn set_backup(&mut self, enabled: bool) {
+        self.backup = enabled;
+    }
+    pub fn set_persistent_undo(&mut self, enabled: bool) {
+        self.persistent_undo = enabled && !self.rows.is_mapped();
+        if enabled && self.revision == 0 {
+            if let Some(history) = self.read_undo_file() {
+                self.history = history;
+            }
+        }
+    }
+    fn undo_file_name(&self) -> Option<PathBuf> {
+        let path = Path::new(self.file_name.as_ref()?);
+        let name = path.file_name()?.to_string_lossy();
+        Some(path.with_file_name(format!(".{}.hecto-undo", name)))
+    }
+    fn undo_file_key(&self) -> Option<String> {
+        let file_name = self.file_name.as_ref()?;
+        let path = fs::canonicalize(file_name).unwrap_or_else(|_| PathBuf::from(file_name));
+        Some(format!(
+            "path {}\nhash {:016x}",
+            path.display(),
+            self.content_hash
+        ))
+    }
+    // Histories recorded against other contents (the file was edited elsewhere
+    // since) cannot be replayed and are ignored until the next save replaces them.
+    fn read_undo_file(&self) -> Option<History> {
+        let contents = fs::read_to_string(self.undo_file_name()?).ok()?;
+        let mut parts = contents.splitn(3, '\n');
+        let key = format!("{}\n{}", parts.next()?, parts.next()?);
+        if key != self.undo_file_key()? {
             return None;
         }
-        let mut position = Position { x: at.x, y: at.y };
-
-        let start = if direction == SearchDirection::Forward {
-            at.y
-        } else {
-            0
+        History::parse(parts.next()?)
+    }
+    fn write_undo_file(&self) -> Result<(), Error> {
+        if let (Some(undo_file_name), Some(key)) = (self.undo_file_name(), self.undo_file_key()) {
+            fs::write(
+                undo_file_name,
+                format!("{}\n{}", key, self.history.serialize()),
+            )?;
+        }
+        Ok(())
+    }
+    fn swap_file_name(&self) -> Option<PathBuf> {
+        let path = Path::new(self.file_name.as_ref()?);
+        let name = path.file_name()?.to_string_lossy();
+        Some(path.with_file_name(format!(".{}.hecto-swp", name)))
+    }
+    // Snapshots the unsaved contents, exactly as saving would write them.
+    // Mapped documents are skipped: copying the whole file every few seconds
+    // would cost more than it protects.
+    pub fn write_swap_file(&self) -> Result<(), Error> {
+        if self.rows.is_mapped() {
+            return Ok(());
+        }
+        if let Some(swap_file_name) = self.swap_file_name() {
+            let mut contents = Vec::new();
+            self.write_to(&mut contents)?;
+            // The swap file holds the file's text, so it is no more readable
+            // than the file. An older one is changed first, as the replacement
+            // takes its permissions.
+            let permissions = self.file_name.as_deref().and_then(swap_permissions);
+            if let Some(permissions) = &permissions {
+                fs::set_permissions(&swap_file_name, permissions.clone()).ok();
+            }
+            write_atomically(&swap_file_name.to_string_lossy(), false, |writer| {
+                if let Some(permissions) = permissions {
+                    writer.get_ref().set_permissions(permissions)?;
+                }
+                writer.write_all(&contents)
+            })?;
+        }
+        Ok(())
+    }
+    pub fn remove_swap_file(&self) {
+        if let Some(swap_file_name) = self.swap_file_name() {
+            fs::remove_file(swap_file_name).ok();
+        }
+    }
+    // A swap file older than the file was left behind before a later save
+    // and has nothing to recover.
+    pub fn has_newer_swap_file(&self) -> bool {
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+        self.swap_file_name().map_or(false, |swap_file_name| {
+            match (modified(&swap_file_name), self.file_name.as_ref()) {
+                (Ok(swap_modified), Some(file_name)) => modified(Path::new(file_name))
+                    .map_or(true, |file_modified| swap_modified > file_modified),
+                _ => false,
+            }
+        })
+    }
+    fn open_swap_file(&self) -> Result<Self, Error> {
+        let swap_file_name = self
+            .swap_file_name()
+            .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "No swap file"))?;
+        // Written as saving would write the file, so in the same encoding.
+        Self::open_with_encoding(&swap_file_name.to_string_lossy(), Some(self.encoding))
+    }
+    // Replaces the contents with the swap file's; the result is unsaved.
+    pub fn recover_swap_file(&mut self) -> Result<(), Error> {
+        let mut recovered = self.open_swap_file()?;
+        recovered.file_name = self.file_name.clone();
+        recovered.file_type = std::mem::take(&mut self.file_type);
+        recovered.content_hash = self.content_hash;
+        recovered.disk_state = self.disk_state;
+        recovered.persistent_undo = self.persistent_undo;
+        recovered.backup = self.backup;
+        recovered.readonly = self.readonly;
+        recovered.dirty = true;
+        *self = recovered;
+        Ok(())
+    }
+    pub fn swap_file_diff(&self) -> Result<String, Error> {
+        Ok(self.describe_changes(&self.open_swap_file()?, "swap file"))
+    }
+    pub fn disk_diff(&self) -> Result<String, Error> {
+        let file_name = self.file_name.as_deref().unwrap_or_default();
+        Ok(self.describe_changes(&Self::open(file_name)?, "file on disk"))
+    }
+    // Describes the lines between the first and the last difference.
+    fn describe_changes(&self, other: &Self, name: &str) -> String {
+        let (len, other_len) = (self.rows.len(), other.rows.len());
+        let mut rows = self.rows.reader();
+        let mut other_rows = other.rows.reader();
+        let mut same = |y: usize, z: usize| {
+            rows.get(y).map(Row::as_bytes) == other_rows.get(z).map(Row::as_bytes)
         };
-        let end = if direction == SearchDirection::Forward {
-            self.rows.len()
+        let mut start = 0;
+        while start < len && start < other_len && same(start, start) {
+            start = start.saturating_add(1);
+        }
+        let (rest, other_rest) = (len.saturating_sub(start), other_len.saturating_sub(start));
+        let mut end = 0;
+        while end < rest
+            && end < other_rest
+            && same(
+                len.saturating_sub(end).saturating_sub(1),
+                other_len.saturating_sub(end).saturating_sub(1),
+            )
+        {
+            end = end.saturating_add(1);
+        }
+        if rest == 0 && other_rest == 0 {
+            return format!("The {} has no changes", name);
+        }
+        format!(
+            "The {} changes line {}: {} lines here, {} in the {}",
+            name,
+            start.saturating_add(1),
+            rest.saturating_sub(end),
+            other_rest.saturating_sub(end),
+            name
+        )
+    }
+    // Whether the file was written by someone else since it was opened or
+    // saved. Only a change of modification time or size is noticed, and if the
+    // contents hash the same it is not a change. A deleted file does not
+    // count, saving simply recreates it.
+    pub fn changed_on_disk(&mut self) -> bool {
+        let file_name = if let Some(file_name) = &self.file_name {
+            file_name
         } else {
-            at.y.saturating_add(1)
+            return false;
         };
-        for _ in start..end {
-            if let Some(row) = self.rows.get(position.y) {
-                if let Some(x) = row.find(&query, position.x, direction) {
-                    position.x = x;
-                    return Some(position);
+        let disk_state = DiskState::read(file_name);
+        if self.disk_state.is_none() || disk_state.is_none() || disk_state == self.disk_state {
+            return false;
+        }
+        if !self.rows.is_mapped() {
+            if let Ok(bytes) = fs::read(file_name) {
+                if content_hash(&bytes) == self.content_hash {
+                    self.disk_state = disk_state;
+                    return false;
                 }
-                if direction == SearchDirection::Forward {
-                    position.y = position.y.saturating_add(1);
-                    position.x = 0;
+            }
+        }
+        true
+    }
+    // Keeps the contents being edited over the changed file, which the next
+    // save overwrites. Mapped documents read from the file as they go, so
+    // they can only be reloaded.
+    pub fn keep_changes(&mut self) -> bool {
+        if self.rows.is_mapped() {
+            return false;
+        }
+        if let Some(file_name) = &self.file_name {
+            self.disk_state = DiskState::read(file_name);
+            if let Ok(bytes) = fs::read(file_name) {
+                self.content_hash = content_hash(&bytes);
+            }
+        }
+        self.dirty = true;
+        true
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+    // The match nearest to `at` in `direction`, from where it starts to where
+    // it ends; a match can span rows. Only the rows from `at` up to a window
+    // are read, and the window doubles until it holds a match that ends well
+    // inside it, so that a match spanning up to `SEARCH_WINDOW` rows is found
+    // without copying the whole document for every search.
+    pub fn find(
+        &self,
+        query: &SearchQuery,
+        at: &Position,
+        direction: SearchDirection,
+    ) -> Option<Range<Position>> {
+        let len = self.rows.len();
+        if at.y >= len {
+            return None;
+        }
+        let mut window = SEARCH_WINDOW;
+        loop {
+            let rows = if direction == SearchDirection::Forward {
+                at.y..at.y.saturating_add(window).min(len)
+            } else {
+                at.y.saturating_sub(window)..at.y.saturating_add(1)
+            };
+            let found = self.stream(rows.clone()).find(query, at, direction);
+            let whole = if direction == SearchDirection::Forward {
+                rows.end == len
+            } else {
+                rows.start == 0
+            };
+            if whole {
+                return found;
+            }
+            if let Some(found) = found {
+                let inside = if direction == SearchDirection::Forward {
+                    found.start.y.saturating_add(SEARCH_WINDOW) < rows.end
                 } else {
-                    position.y = position.y.saturating_sub(1);
-                    position.x = self.rows[position.y].len();
+                    found.start.y >= rows.start.saturating_add(SEARCH_WINDOW)
+                };
+                if inside {
+                    return Some(found);
                 }
+            }
+            window = window.saturating_mul(2);
+        }
+    }
+    // Like `find`, going on from the other end of the document when nothing
+    // is found; tells whether it did.
+    pub fn find_wrapping(
+        &self,
+        query: &SearchQuery,
+        at: &Position,
+        direction: SearchDirection,
+    ) -> Option<(Range<Position>, bool)> {
+        if let Some(found) = self.find(query, at, direction) {
+            return Some((found, false));
+        }
+        let start = if direction == SearchDirection::Forward {
+            Position::default()
+        } else {
+            let y = self.rows.len().saturating_sub(1);
+            Position {
+                x: self.rows.reader().get(y).map_or(0, Row::len),
+                y,
+            }
+        };
+        self.find(query, &start, direction)
+            .map(|found| (found, true))
+    }
+    // How many matches there are up to and including the one at `at`, and
+    // in all. Large files would have to be read whole for every key typed,
+    // so their matches are not counted.
+    pub fn count_matches(&mut self, query: &SearchQuery, at: &Position) -> Option<(usize, usize)> {
+        if self.rows.is_mapped() {
+            return None;
+        }
+        let cached = matches!(
+            &self.matches,
+            Some((revision, cached, _)) if *revision == self.revision && cached == query
+        );
+        if !cached {
+            let matches = self.stream(0..self.rows.len()).matches(query);
+            self.matches = Some((self.revision, query.clone(), matches));
+        }
+        let matches = self.matches.as_ref().map(|(_, _, matches)| matches)?;
+        let before = matches
+            .iter()
+            .filter(|found| found.y < at.y || (found.y == at.y && found.x <= at.x))
+            .count();
+        Some((before, matches.len()))
+    }
+    // Replaces the match of `query` that starts at `at` with `template`
+    // expanded for it, and returns the position after the replacement.
+    pub fn replace(
+        &mut self,
+        query: &SearchQuery,
+        at: &Position,
+        template: &str,
+    ) -> Option<Position> {
+        let rows = at.y..at.y.saturating_add(SEARCH_WINDOW).min(self.rows.len());
+        let (found, replacement) = self.stream(rows).replacement(query, at, template)?;
+        self.dirty = true;
+        self.delete_range(&found);
+        let after = self.insert_text(at, &replacement);
+        self.unhighlight_rows(at.y);
+        Some(after)
+    }
+    // Deletes the text from `range.start` up to `range.end`, which can be on
+    // a later row.
+    fn delete_range(&mut self, range: &Range<Position>) {
+        let at = &range.start;
+        for y in at.y..=range.end.y {
+            self.load_around(y);
+        }
+        let len = self.rows.get(at.y).map_or(0, Row::len);
+        let end = if range.end.y == at.y { range.end.x } else { len };
+        self.remove_at(at, end.saturating_sub(at.x));
+        let next = Position {
+            x: 0,
+            y: at.y.saturating_add(1),
+        };
+        for y in at.y..range.end.y {
+            let count = if y.saturating_add(1) == range.end.y {
+                range.end.x
             } else {
-                return None;
+                self.rows.get(next.y).map_or(0, Row::len)
+            };
+            self.remove_at(&next, count);
+            self.join_rows(at.y);
+        }
+    }
+    // Inserts `text`, which can hold newlines, and returns the position
+    // after it.
+    fn insert_text(&mut self, at: &Position, text: &str) -> Position {
+        let mut position = at.clone();
+        for (index, line) in text.split('\n').enumerate() {
+            if index > 0 {
+                self.edit(Operation::Split {
+                    at: position.clone(),
+                    ending: self.ending_at(position.y),
+                });
+                position = Position {
+                    x: 0,
+                    y: position.y.saturating_add(1),
+                };
+            }
+            if !line.is_empty() {
+                let len = self.rows.get(position.y).map_or(0, Row::len);
+                self.insert_str_at(&position, line);
+                let added = self.rows.get(position.y).map_or(0, Row::len).saturating_sub(len);
+                position.x = position.x.saturating_add(added);
             }
         }
-        None
+        position
     }
-    pub fn highlight(&mut self, word: &Option<String>, until: Option<usize>) {
-        let mut start_with_comment = false;
-        let until = if let Some(until) = until {
-            if until.saturating_add(1) < self.rows.len() {
-                until.saturating_add(1)
+    // The given rows as one text to search.
+    fn stream(&self, rows: Range<usize>) -> TextStream {
+        let mut stream = TextStream::new(rows.start);
+        let mut reader = self.rows.reader();
+        for y in rows {
+            stream.push_line(reader.get(y).map_or("", Row::as_str));
+        }
+        stream
+    }
+    // Rewrites the indentation at the start of every row with spaces only, or
+    // with as many tabs as fit and spaces for the rest, as one undo step.
+    // Returns how many rows changed, or `None` for a large file, which would
+    // have to be read whole.
+    #[allow(clippy::integer_arithmetic, clippy::integer_division)]
+    pub fn retab(&mut self, expand: bool) -> Option<usize> {
+        if self.rows.is_mapped() {
+            return None;
+        }
+        let tab_width = width::tab_width();
+        let mut changed = 0_usize;
+        self.begin_change();
+        for y in 0..self.rows.len() {
+            let indent: String = match self.rows.get(y) {
+                Some(row) => row
+                    .as_str()
+                    .graphemes(true)
+                    .take_while(|grapheme| *grapheme == " " || *grapheme == "\t")
+                    .collect(),
+                None => continue,
+            };
+            let columns = indent.graphemes(true).fold(0, width::advance);
+            let retabbed = if expand {
+                " ".repeat(columns)
             } else {
-                self.rows.len()
+                format!("{}{}", "\t".repeat(columns / tab_width), " ".repeat(columns % tab_width))
+            };
+            if retabbed == indent {
+                continue;
             }
-        } else {
-            self.rows.len()
-        };
-        #[allow(clippy::indexing_slicing)]
-        for row in &mut self.rows[..until] {
+            if let Some(mut edited) = self.rows.get(y).cloned() {
+                edited.remove(0, indent.len());
+                edited.insert_str(0, &retabbed);
+                self.replace_row(y, &edited);
+            }
+            changed += 1;
+        }
+        self.end_change();
+        if changed > 0 {
+            self.dirty = true;
+            self.unhighlight_rows(0);
+        }
+        Some(changed)
+    }
+    // Edits between these calls are undone in a single step.
+    pub fn begin_change(&mut self) {
+        self.history.begin_group();
+    }
+    pub fn end_change(&mut self) {
+        self.history.end_group();
+    }
+    // Highlights every row up to the end of `visible`. Matches of `word` are
+    // only looked for in and around the visible rows, as rows off screen
+    // are highlighted again once they scroll into view. The `current` match
+    // stands out from the others.
+    pub fn highlight(
+        &mut self,
+        word: &Option<SearchQuery>,
+        current: Option<&Range<Position>>,
+        visible: &Range<usize>,
+    ) {
+        let mut start_with_comment = false;
+        let until = visible.end.saturating_add(1).min(self.rows.len());
+        self.highlighted_until = cmp::max(self.highlighted_until, until);
+        let first = visible.start.saturating_sub(SEARCH_WINDOW);
+        let highlights = word.as_ref().map(|word| {
+            let last = until.saturating_add(SEARCH_WINDOW).min(self.rows.len());
+            self.stream(first..last).highlights(word, current)
+        });
+        for (y, row) in self.rows.loaded_mut(until) {
+            let matches = highlights.as_ref().and_then(|highlights| {
+                y.checked_sub(first)
+                    .and_then(|index| highlights.get(index))
+                    .cloned()
+            });
             start_with_comment = row.highlight(
                 &self.file_type.highlighting_options(),
-                word,
+                matches.as_deref(),
                 start_with_comment,
             );
         }
     }
-    fn should_create_new_state(&mut self) -> bool {
-        if let Some(last_time) = self.last_edit_time {
-            if last_time.elapsed() > self.batch_duration {
-                self.last_edit_time = Some(Instant::now());
-                true
-            } else {
-                false
+    fn ending_at(&self, y: usize) -> Option<LineEnding> {
+        self.rows.get(y).and_then(Row::ending)
+    }
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+    pub fn set_cursors(&mut self, before: &Position, after: &Position) {
+        self.history.set_cursors(before, after);
+    }
+    // Edits can land outside the viewport (undo, for one), so the rows around
+    // them are read in first when the document is mapped.
+    fn load_around(&mut self, y: usize) {
+        self.rows
+            .load(y.saturating_sub(1), y.saturating_add(2))
+            .ok();
+    }
+    fn edit(&mut self, operation: Operation) {
+        self.apply(&operation);
+        self.record(operation);
+    }
+    // Records an operation that has already been applied.
+    fn record(&mut self, operation: Operation) {
+        self.history.record(operation);
+        self.revision = self.revision.wrapping_add(1);
+    }
+    #[allow(clippy::integer_arithmetic)]
+    fn apply(&mut self, operation: &Operation) {
+        self.load_around(operation.position().y);
+        match operation {
+            Operation::Insert { at, text } => {
+                if let Some(row) = self.rows.edit(at.y) {
+                    row.insert_str(at.x, text);
+                }
+            }
+            Operation::Delete { at, text } => {
+                if let Some(row) = self.rows.edit(at.y) {
+                    row.remove(at.x, text.graphemes(true).count());
+                }
+            }
+            Operation::Replace { at, old, new } => {
+                if let Some(row) = self.rows.edit(at.y) {
+                    row.replace(at.x, old.graphemes(true).count(), new);
+                }
+            }
+            Operation::Split { at, ending } => {
+                if let Some(row) = self.rows.edit(at.y) {
+                    let new_row = row.split(at.x);
+                    row.set_ending(*ending);
+                    self.rows.insert(at.y + 1, new_row);
+                }
+            }
+            Operation::Join { at, .. } => {
+                if let Some(next_row) = self.rows.remove(at.y + 1) {
+                    if let Some(row) = self.rows.edit(at.y) {
+                        row.append(&next_row);
+                    }
+                }
+            }
+            Operation::SetEndings { to, rows, .. } => {
+                for (y, _, ending) in rows {
+                    if let Some(row) = self.rows.edit(*y) {
+                        row.set_ending(*ending);
+                    }
+                }
+                self.line_ending = *to;
+                let mut reader = self.rows.reader();
+                let endings = (0..self.rows.len()).filter_map(|y| reader.get(y)?.ending());
+                self.mixed_endings = line_ending::detect(endings).1;
+            }
+            Operation::InsertRow { y } => self.rows.insert(*y, Row::default()),
+            Operation::RemoveRow { y } => {
+                self.rows.remove(*y);
             }
-        } else {
-            self.last_edit_time = Some(Instant::now());
-            true
         }
     }
-    fn save_state(&mut self, cursor_position: &Position) {
-        if self.should_create_new_state() {
-            self.history.truncate(self.history_index + 1);
-            self.history.push((self.rows.clone(), cursor_position.clone()));
-            self.history_index = self.history.len() - 1;
-        } else {
-            self.history[self.history_index] = (self.rows.clone(), cursor_position.clone());
+    fn undo_change(&mut self, change: &Change) -> Position {
+        for operation in change.operations().iter().rev() {
+            self.apply(&operation.inverse());
         }
+        self.dirty = true;
+        self.revision = self.revision.wrapping_add(1);
+        self.unhighlight_rows(change.first_row());
+        change.cursor_before()
     }
-    pub fn undo(&mut self) -> Option<Position> {
-        if self.history_index > 0 {
-            self.history_index -= 1;
-            let (rows, position) = &self.history[self.history_index];
-            self.rows = rows.clone();
-            self.last_edit_time = None;
-            Some(position.clone())
-        } else {
-            None
+    fn redo_change(&mut self, change: &Change) -> Position {
+        for operation in change.operations() {
+            self.apply(operation);
         }
+        self.dirty = true;
+        self.revision = self.revision.wrapping_add(1);
+        self.unhighlight_rows(change.first_row());
+        change.cursor_after()
+    }
+    pub fn undo(&mut self) -> Option<Position> {
+        let change = self.history.undo()?.clone();
+        Some(self.undo_change(&change))
     }
     pub fn redo(&mut self) -> Option<Position> {
-        if self.history_index + 1 < self.history.len() {
-            self.history_index += 1;
-            let (rows, position) = &self.history[self.history_index];
-            self.rows = rows.clone();
-            self.last_edit_time = None;
-            Some(position.clone())
-        } else {
-            None
+        let change = self.history.redo()?.clone();
+        Some(self.redo_change(&change))
+    }
+    pub fn goto_state(&mut self, state: usize) -> Option<Position> {
+        let mut position = None;
+        for step in self.history.travel(state) {
+            position = Some(match step {
+                Step::Undo(change) => self.undo_change(&change),
+                Step::Redo(change) => self.redo_change(&change),
+            });
         }
+        position
+    }
+    pub fn earlier(&mut self) -> Option<Position> {
+        let state = self.history.state().checked_sub(1)?;
+        self.goto_state(state)
+    }
+    pub fn later(&mut self) -> Option<Position> {
+        self.goto_state(self.history.state().saturating_add(1))
+    }
+    pub fn undo_state(&self) -> usize {
+        self.history.state()
+    }
+    pub fn undo_branches(&self) -> Vec<Branch> {
+        self.history.branches()
     }
     pub fn default() -> Self {
         Self {
-            rows: Vec::new(),
+            rows: Rope::default(),
             file_name: None,
             dirty: false,
             file_type: FileType::default(),
-            history: vec![(Vec::new(), Position::default())],
-            history_index: 0,
-            last_edit_time: None,
-            batch_duration: Duration::from_millis(1000),
+            history: History::default(),
+            revision: 0,
+            persistent_undo: false,
+            content_hash: 0,
+            highlighted_until: 0,
+            line_ending: LineEnding::default(),
+            mixed_endings: false,
+            final_newline: true,
+            encoding: TextEncoding::default(),
+            backup: false,
+            disk_state: None,
+            readonly: false,
+            matches: None,
+        }
+    }
+}
+
+// The FNV-1a hash of nothing, which `extend_hash` goes on from.
+pub const EMPTY_HASH: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn content_hash(bytes: &[u8]) -> u64 {
+    extend_hash(EMPTY_HASH, bytes)
+}
+// The hash of some bytes followed by `bytes`, given the hash of the first.
+pub fn extend_hash(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// The permissions of `file_name`, kept writable by its owner so that the next
+// snapshot can replace a swap file made with them.
+fn swap_permissions(file_name: &str) -> Option<fs::Permissions> {
+    let mut permissions = fs::metadata(file_name).ok()?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(permissions.mode() | 0o200);
+    }
+    #[cfg(not(unix))]
+    permissions.set_readonly(false);
+    Some(permissions)
+}
+
+// The operation that puts `new` in place of `old` at `at`. When there are
+// both, it is one `Replace`: taking `old` out first could let the graphemes
+// around it join, and `at` would no longer point between them.
+fn replacement(at: Position, old: String, new: String) -> Option<Operation> {
+    match (old.is_empty(), new.is_empty()) {
+        (true, true) => None,
+        (true, false) => Some(Operation::Insert { at, text: new }),
+        (false, true) => Some(Operation::Delete { at, text: old }),
+        (false, false) => Some(Operation::Replace { at, old, new }),
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn text(document: &Document) -> Vec<String> {
+        (0..document.len())
+            .filter_map(|y| document.row(y))
+            .map(|row| String::from_utf8_lossy(row.as_bytes()).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_undo_redo_operations() {
+        let mut document = Document::default();
+        for (x, c) in "ab".chars().enumerate() {
+            document.insert(&Position { x, y: 0 }, c);
+        }
+        document.insert(&Position { x: 1, y: 0 }, '\n');
+        document.delete(&Position { x: 0, y: 1 });
+        document.set_cursors(&Position { x: 3, y: 4 }, &Position { x: 0, y: 1 });
+        assert_eq!(text(&document), vec!["a", ""]);
+        let before = document.undo().unwrap_or_default();
+        assert_eq!((before.x, before.y), (3, 4));
+        assert!(text(&document).is_empty());
+        assert!(document.undo().is_none());
+        let after = document.redo().unwrap_or_default();
+        assert_eq!((after.x, after.y), (0, 1));
+        assert_eq!(text(&document), vec!["a", ""]);
+    }
+
+    #[test]
+    fn test_undo_insert_and_delete() {
+        let mut document = Document::from_bytes(b"one\ntwo\n", None);
+        document.begin_change();
+        let end = document.insert_text(&Position { x: 3, y: 0 }, "1\nnew ");
+        document.end_change();
+        assert_eq!((end.x, end.y), (4, 1));
+        assert_eq!(text(&document), vec!["one1", "new ", "two"]);
+        document.begin_change();
+        document.delete_range(&(Position { x: 2, y: 0 }..Position { x: 1, y: 2 }));
+        document.end_change();
+        assert_eq!(text(&document), vec!["onwo"]);
+        document.undo();
+        assert_eq!(text(&document), vec!["one1", "new ", "two"]);
+        document.undo();
+        assert_eq!(text(&document), vec!["one", "two"]);
+        document.redo();
+        document.redo();
+        assert_eq!(text(&document), vec!["onwo"]);
+        assert_eq!(document.undo_state(), 2);
+    }
+
+    #[test]
+    fn test_undo_combining_mark() {
+        let mut document = Document::from_bytes(b"ex", None);
+        document.insert(&Position { x: 1, y: 0 }, '\u{301}');
+        assert_eq!(text(&document), vec!["e\u{301}x"]);
+        assert_eq!(document.row(0).map(Row::len), Some(2));
+        document.insert(&Position { x: 1, y: 0 }, 'y');
+        assert_eq!(text(&document), vec!["e\u{301}yx"]);
+        document.undo();
+        assert_eq!(text(&document), vec!["ex"]);
+        document.redo();
+        assert_eq!(text(&document), vec!["e\u{301}yx"]);
+    }
+
+    #[test]
+    fn test_undo_delete_that_joins_graphemes() {
+        let mut document = Document::from_bytes("é\t\u{301}ll".as_bytes(), None);
+        document.delete(&Position { x: 1, y: 0 });
+        assert_eq!(text(&document), vec!["é\u{301}ll"]);
+        assert_eq!(document.row(0).map(Row::len), Some(3));
+        document.undo();
+        assert_eq!(text(&document), vec!["é\t\u{301}ll"]);
+
+        let mut document = Document::from_bytes(" 中👍\n\u{301}\n".as_bytes(), None);
+        document.delete(&Position { x: 3, y: 0 });
+        assert_eq!(text(&document), vec![" 中👍\u{301}"]);
+        document.undo();
+        assert_eq!(text(&document), vec![" 中👍", "\u{301}"]);
+        document.redo();
+        assert_eq!(text(&document), vec![" 中👍\u{301}"]);
+    }
+
+    #[test]
+    fn test_replace_is_one_step() {
+        let mut document = Document::from_bytes("x = 1\n中 = 22\n".as_bytes(), None);
+        let options = crate::SearchOptions {
+            regex: true,
+            ..crate::SearchOptions::default()
+        };
+        let query = SearchQuery::new(r"(\S+) = (\d+)", options).unwrap();
+        let mut at = Position::default();
+        document.begin_change();
+        while let Some(found) = document.find(&query, &at, SearchDirection::Forward) {
+            at = document.replace(&query, &found.start, "$2 = $1").unwrap();
+        }
+        document.end_change();
+        assert_eq!(text(&document), vec!["1 = x", "22 = 中"]);
+        document.undo();
+        assert_eq!(text(&document), vec!["x = 1", "中 = 22"]);
+    }
+
+    #[test]
+    fn test_replace_across_rows() {
+        let mut document = Document::from_bytes("a broken\nphrase 中\nend\n".as_bytes(), None);
+        let options = crate::SearchOptions {
+            regex: true,
+            ..crate::SearchOptions::default()
+        };
+        let query = SearchQuery::new(r"broken\s+phrase", options).unwrap();
+        let found = document
+            .find(&query, &Position::default(), SearchDirection::Forward)
+            .unwrap();
+        assert_eq!((found.end.x, found.end.y), (6, 1));
+        document.begin_change();
+        let after = document.replace(&query, &found.start, "one\nline").unwrap();
+        document.end_change();
+        assert_eq!((after.x, after.y), (4, 1));
+        assert_eq!(text(&document), vec!["a one", "line 中", "end"]);
+        let query = SearchQuery::new(r"one\nline 中\ne", options).unwrap();
+        let found = document
+            .find(&query, &Position::default(), SearchDirection::Forward)
+            .unwrap();
+        document.begin_change();
+        document.replace(&query, &found.start, "");
+        document.end_change();
+        assert_eq!(text(&document), vec!["a nd"]);
+        document.undo();
+        assert_eq!(text(&document), vec!["a one", "line 中", "end"]);
+        let query = SearchQuery::new("aa", crate::SearchOptions::default()).unwrap();
+        let mut document = Document::from_bytes("aaaa\n".as_bytes(), None);
+        assert_eq!(document.count_matches(&query, &Position { x: 1, y: 0 }), Some((2, 3)));
+    }
+
+    #[test]
+    fn test_retab() {
+        let mut document = Document::from_bytes("\tx\n  \t y\n      z\nw\t\n".as_bytes(), None);
+        assert_eq!(document.retab(true), Some(2));
+        assert_eq!(text(&document), vec!["    x", "     y", "      z", "w\t"]);
+        assert_eq!(document.retab(false), Some(3));
+        assert_eq!(text(&document), vec!["\tx", "\t y", "\t  z", "w\t"]);
+        document.undo();
+        assert_eq!(text(&document), vec!["    x", "     y", "      z", "w\t"]);
+        assert!(!Document::from_bytes(b"", None).expand_tab());
+    }
+
+    #[test]
+    fn test_find_wrapping() {
+        let mut document = Document::from_bytes("中文 x\nx 中文\n".as_bytes(), None);
+        let query = SearchQuery::new("中文", crate::SearchOptions::default()).unwrap();
+        let at = Position { x: 3, y: 1 };
+        let (found, wrapped) = document
+            .find_wrapping(&query, &at, SearchDirection::Forward)
+            .unwrap();
+        assert_eq!((found.start.x, found.start.y, wrapped), (0, 0, true));
+        assert_eq!(document.count_matches(&query, &found.start), Some((1, 2)));
+        let (found, wrapped) = document
+            .find_wrapping(&query, &Position::default(), SearchDirection::Backward)
+            .unwrap();
+        assert_eq!((found.start.x, found.start.y, wrapped), (2, 1, true));
+        assert_eq!(document.count_matches(&query, &found.start), Some((2, 2)));
+        document.insert(&Position { x: 1, y: 0 }, ' ');
+        assert_eq!(document.count_matches(&query, &found.start), Some((1, 1)));
+    }
+
+    #[test]
+    fn test_find_beyond_window() {
+        let text = format!("end\n{}broken\nphrase\n", "x\n".repeat(SEARCH_WINDOW * 3));
+        let document = Document::from_bytes(text.as_bytes(), None);
+        let regex = crate::SearchOptions {
+            regex: true,
+            ..crate::SearchOptions::default()
+        };
+        let query = SearchQuery::new(r"broken\s+phrase", regex).unwrap();
+        let found = document
+            .find(&query, &Position::default(), SearchDirection::Forward)
+            .unwrap();
+        assert_eq!((found.start.y, found.end.y), (SEARCH_WINDOW * 3 + 1, SEARCH_WINDOW * 3 + 2));
+        let query = SearchQuery::new("end", regex).unwrap();
+        let found = document
+            .find(&query, &found.end, SearchDirection::Backward)
+            .unwrap();
+        assert_eq!((found.start.x, found.start.y), (0, 0));
+    }
+
+    #[test]
+    fn test_persistent_undo() {
+        let path = std::env::temp_dir().join(format!("hecto-undo-{}.txt", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        fs::write(&path, "ab\n").unwrap();
+        let mut document = Document::open(&file_name).unwrap();
+        document.set_persistent_undo(true);
+        document.insert(&Position { x: 2, y: 0 }, 'c');
+        document.save().unwrap();
+
+        let mut reopened = Document::open(&file_name).unwrap();
+        reopened.set_persistent_undo(true);
+        assert!(reopened.undo().is_some());
+        assert_eq!(text(&reopened), vec!["ab"]);
+
+        fs::write(&path, "abc\nchanged elsewhere\n").unwrap();
+        let mut stale = Document::open(&file_name).unwrap();
+        stale.set_persistent_undo(true);
+        assert!(stale.undo().is_none());
+
+        if let Some(undo_file_name) = document.undo_file_name() {
+            fs::remove_file(undo_file_name).ok();
+        }
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_line_endings_round_trip() {
+        let path = std::env::temp_dir().join(format!("hecto-endings-{}.txt", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        fs::write(&path, "a\r\nb\nc\r\nd").unwrap();
+        let mut document = Document::open(&file_name).unwrap();
+        assert_eq!(document.line_ending(), "Mixed");
+        document.insert(&Position { x: 1, y: 0 }, '\n');
+        document.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\r\n\r\nb\nc\r\nd");
+
+        assert!(document.set_line_ending(LineEnding::Lf));
+        document.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\n\nb\nc\nd");
+        assert_eq!(document.line_ending(), "LF");
+        document.undo();
+        assert_eq!(document.line_ending(), "Mixed");
+        let mut output = Vec::new();
+        document.write_to(&mut output).unwrap();
+        assert_eq!(output, b"a\r\n\r\nb\nc\r\nd");
+        document.redo();
+        assert_eq!(document.line_ending(), "LF");
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_undo_join_keeps_ending() {
+        let mut document = Document::from_bytes(b"a\r\nb\nc\n", None);
+        document.delete(&Position { x: 1, y: 0 });
+        let mut output = Vec::new();
+        document.write_to(&mut output).unwrap();
+        assert_eq!(output, b"ab\nc\n");
+        document.undo();
+        let mut output = Vec::new();
+        document.write_to(&mut output).unwrap();
+        assert_eq!(output, b"a\r\nb\nc\n");
+        document.redo();
+        document.undo();
+        let mut output = Vec::new();
+        document.write_to(&mut output).unwrap();
+        assert_eq!(output, b"a\r\nb\nc\n");
+    }
+
+    #[test]
+    fn test_encoding_round_trip() {
+        let path = std::env::temp_dir().join(format!("hecto-gbk-{}.txt", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        let gbk = TextEncoding::from_label("gbk").unwrap();
+        let mut bytes = Vec::new();
+        gbk.encode("中文文本\n第二行\n", &mut bytes).unwrap();
+        fs::write(&path, &bytes).unwrap();
+        let mut document = Document::open(&file_name).unwrap();
+        assert_eq!(document.encoding(), "gb18030");
+        assert_eq!(text(&document), vec!["中文文本", "第二行"]);
+        document.insert(&Position { x: 4, y: 0 }, '！');
+        document.save().unwrap();
+        bytes.clear();
+        gbk.encode("中文文本！\n第二行\n", &mut bytes).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+
+        assert!(document.set_encoding(TextEncoding::from_label("latin1").unwrap()));
+        assert!(document.save().is_err());
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let mut document = Document::from_bytes(b"one\r\ntwo", None);
+        assert!(document.file_name.is_none());
+        assert_eq!(text(&document), vec!["one", "two"]);
+        document.insert(&Position { x: 3, y: 1 }, 's');
+        let mut output = Vec::new();
+        document.write_to(&mut output).unwrap();
+        assert_eq!(output, b"one\r\ntwos");
+    }
+
+    #[test]
+    fn test_save_as() {
+        let directory = std::env::temp_dir();
+        let old_name = directory.join(format!("hecto-save-as-{}", std::process::id()));
+        let new_name = directory.join(format!("hecto-save-as-{}.rs", std::process::id()));
+        fs::write(&old_name, "fn main() {}\n").unwrap();
+        let mut document = Document::open(&old_name.to_string_lossy()).unwrap();
+        assert_eq!(document.file_type(), "No filetype");
+        document.insert(&Position { x: 0, y: 1 }, '/');
+        document.save_as(new_name.to_string_lossy().to_string()).unwrap();
+        assert_eq!(document.file_type(), "Rust");
+        assert!(!document.is_dirty());
+        assert_eq!(fs::read_to_string(&old_name).unwrap(), "fn main() {}\n");
+        assert_eq!(fs::read_to_string(&new_name).unwrap(), "fn main() {}\n/\n");
+
+        let missing = directory.join("hecto-missing-directory").join("file.txt");
+        assert!(document.save_as(missing.to_string_lossy().to_string()).is_err());
+        assert_eq!(document.file_name, Some(new_name.to_string_lossy().to_string()));
+        fs::remove_file(old_name).ok();
+        fs::remove_file(new_name).ok();
+    }
+
+    #[test]
+    fn test_swap_file_recovery() {
+        let path = std::env::temp_dir().join(format!("hecto-swap-{}.txt", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let earlier = std::time::SystemTime::now() - std::time::Duration::from_secs(10);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(earlier).unwrap();
+        let mut document = Document::open(&file_name).unwrap();
+        document.insert(&Position { x: 3, y: 1 }, 's');
+        document.write_swap_file().unwrap();
+
+        let mut reopened = Document::open(&file_name).unwrap();
+        assert!(reopened.has_newer_swap_file());
+        assert_eq!(
+            reopened.swap_file_diff().unwrap(),
+            "The swap file changes line 2: 1 lines here, 1 in the swap file"
+        );
+        reopened.recover_swap_file().unwrap();
+        assert!(reopened.is_dirty());
+        assert_eq!(text(&reopened), vec!["one", "twos", "three"]);
+        reopened.save().unwrap();
+        assert!(!reopened.has_newer_swap_file());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwos\nthree\n");
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_swap_file_keeps_encoding_and_mode() {
+        let path = std::env::temp_dir().join(format!("hecto-swap-latin-{}.txt", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        fs::write(&path, b"caf\xc3\xa9\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let encoding = TextEncoding::from_label("windows-1252");
+        let mut document = Document::open_with_encoding(&file_name, encoding).unwrap();
+        document.insert(&Position { x: 0, y: 1 }, 'x');
+        document.write_swap_file().unwrap();
+        let swap_file_name = document.swap_file_name().unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&swap_file_name).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let mut reopened = Document::open_with_encoding(&file_name, encoding).unwrap();
+        reopened.recover_swap_file().unwrap();
+        assert_eq!(text(&reopened), vec!["caf\u{c3}\u{a9}", "x"]);
+        fs::remove_file(swap_file_name).ok();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_changed_on_disk() {
+        let path = std::env::temp_dir().join(format!("hecto-disk-{}.txt", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        fs::write(&path, "ours\n").unwrap();
+        let mut document = Document::open(&file_name).unwrap();
+        let touched = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(touched).unwrap();
+        assert!(!document.changed_on_disk());
+
+        fs::write(&path, "theirs\n").unwrap();
+        assert!(document.changed_on_disk());
+        assert_eq!(
+            document.disk_diff().unwrap(),
+            "The file on disk changes line 1: 1 lines here, 1 in the file on disk"
+        );
+        assert!(document.keep_changes());
+        assert!(!document.changed_on_disk());
+        assert!(document.is_dirty());
+
+        fs::write(&path, "theirs again\n").unwrap();
+        assert!(document.changed_on_disk());
+        document.reload(None).unwrap();
+        assert!(!document.changed_on_disk());
+        assert_eq!(text(&document), vec!["theirs again"]);
+        fs::remove_file(path).ok();
+    }
+
+    // Edits cost about the size of the edit, not of the row or the file;
+    // re-segmenting whole rows would take these well past the bound.
+    #[test]
+    fn test_typing_in_long_line() {
+        let mut document = Document::default();
+        let start = std::time::Instant::now();
+        for x in 0..10_000 {
+            document.insert(&Position { x, y: 0 }, 'a');
+        }
+        for x in 0..10_000 {
+            document.insert(&Position { x: x * 2, y: 0 }, '界');
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(document.row(0).map(Row::len), Some(20_000));
+        assert_eq!(document.row(0).map(|row| row.get_width_to(4)), Some(6));
+    }
+
+    #[test]
+    fn test_newlines_in_large_document() {
+        let path = std::env::temp_dir().join(format!("hecto-newlines-{}.txt", std::process::id()));
+        fs::write(&path, "some text on a line\n".repeat(200_000)).unwrap();
+        let mut document = Document::open(&path.to_string_lossy()).unwrap();
+        let start = std::time::Instant::now();
+        for y in 0..10_000 {
+            document.insert(&Position { x: 4, y: y * 2 }, '\n');
         }
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(document.len(), 210_000);
+        assert_eq!(document.row(19_998).map(Row::as_str), Some("some"));
+        assert_eq!(document.row(19_999).map(Row::as_str), Some(" text on a line"));
+        assert_eq!(document.row(20_000).map(Row::as_str), Some("some text on a line"));
+        fs::remove_file(path).ok();
     }
 }
@@ -1,22 +1,39 @@
+use crate::completion::{common_prefix, complete_path};
+use crate::grep::grep;
+use crate::prompt::{LineInput, PromptHistory};
+use crate::Args;
 use crate::Document;
+use crate::LineEnding;
+use crate::TextEncoding;
 use crate::Row;
+use crate::SearchOptions;
+use crate::SearchQuery;
 use crate::Terminal;
+use crate::width;
+use std::collections::{HashMap, VecDeque};
 use std::env;
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
 use std::time::Duration;
 use std::time::Instant;
-use console::style;
+use std::time::SystemTime;
+use console::{measure_text_width, style, truncate_str};
 use crossterm::{
     style::{Color, ResetColor, SetForegroundColor},
     event::{self,Event,KeyCode, KeyModifiers, KeyEvent, MouseEventKind, MouseButton, MouseEvent},
     ExecutableCommand,
 };
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 
 const STATUS_FG_COLOR: Color = Color::Rgb { r: 63, g: 63, b: 63 };
 const STATUS_BG_COLOR: Color = Color::Rgb { r: 239, g: 239, b: 239 };
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const QUIT_TIMES: u8 = 3;
 const MAX_LINE_LEN: usize = 50;
+const TICK: Duration = Duration::from_millis(100);
+const SWAP_INTERVAL: Duration = Duration::from_secs(4);
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum SearchDirection {
@@ -35,6 +52,57 @@ struct StatusMessage {
     time: Instant,
 }
 
+// What a prompt asks for; each kind keeps its own history, except for
+// answers to questions like "Overwrite? (y/N)". A replacement can be empty,
+// so only Esc cancels it.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum PromptKind {
+    Answer,
+    Text,
+    Path,
+    Search,
+    Replacement,
+}
+
+// The paths a Tab in a path prompt could mean, and the one last shown.
+struct Completion {
+    candidates: Vec<String>,
+    // Where the names start in each candidate, after the directory.
+    start: usize,
+    selected: Option<usize>,
+}
+
+impl Completion {
+    fn cycle(&mut self, forward: bool) -> &str {
+        let len = self.candidates.len();
+        let selected = match (self.selected, forward) {
+            (None, true) => 0,
+            (None, false) => len.saturating_sub(1),
+            (Some(index), true) => index.saturating_add(1) % len,
+            (Some(index), false) => index.checked_sub(1).unwrap_or(len.saturating_sub(1)),
+        };
+        self.selected = Some(selected);
+        self.candidates.get(selected).map_or("", String::as_str)
+    }
+    // The candidates by name, the selected one highlighted.
+    fn hint(&self) -> String {
+        let names: Vec<String> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .map(|(index, candidate)| {
+                let name = candidate.get(self.start..).unwrap_or(candidate);
+                if Some(index) == self.selected {
+                    style(name).reverse().to_string()
+                } else {
+                    name.to_string()
+                }
+            })
+            .collect();
+        format!("  [{}]", names.join(" "))
+    }
+}
+
 pub struct Language {
     pub name: String,
 }
@@ -48,6 +116,42 @@ impl StatusMessage {
     }
 }
 
+// An open document and the editor state that belongs to it.
+struct Buffer {
+    document: Document,
+    cursor_position: Position,
+    offset: Position,
+    highlighted_word: Option<SearchQuery>,
+    swap_revision: usize,
+    swap_time: Instant,
+    results: Option<Results>,
+    // Whether the file was seen to change on disk while the buffer was
+    // parked, which is told once and resolved when switching to it.
+    changed_on_disk: bool,
+}
+
+// What a buffer of search results was made from: the query, and the file and
+// line that each of its rows stands for.
+struct Results {
+    query: SearchQuery,
+    targets: Vec<(String, usize)>,
+}
+
+impl From<Document> for Buffer {
+    fn from(document: Document) -> Self {
+        Self {
+            document,
+            cursor_position: Position::default(),
+            offset: Position::default(),
+            highlighted_word: None,
+            swap_revision: 0,
+            swap_time: Instant::now(),
+            results: None,
+            changed_on_disk: false,
+        }
+    }
+}
+
 pub struct Editor {
     should_quit: bool,
     terminal: Terminal,
@@ -56,52 +160,445 @@ pub struct Editor {
     document: Document,
     status_message: StatusMessage,
     quit_times: u8,
-    highlighted_word: Option<String>,
+    highlighted_word: Option<SearchQuery>,
+    // The revision and time of the last swap file snapshot.
+    swap_revision: usize,
+    swap_time: Instant,
+    disk_check_time: Instant,
+    // The other open buffers. The current one lives in the fields above and
+    // counts as being at index `current` of the list.
+    buffers: Vec<Buffer>,
+    current: usize,
+    prompt_history: HashMap<PromptKind, PromptHistory>,
+    search_options: SearchOptions,
+    // Where the cursor is on the message bar while a prompt is shown.
+    prompt_cursor: Option<usize>,
+    // Shown after the text typed into a prompt, set by its callback.
+    prompt_note: String,
+    results: Option<Results>,
+    // The match a replace is waiting to be told what to do with.
+    current_match: Option<Range<Position>>,
 }
 
 impl Editor {
     pub fn run(&mut self) {
         std::io::stdout().execute(crossterm::event::EnableMouseCapture).unwrap();
-        
+        if self.document.has_newer_swap_file() {
+            if let Err(error) = self.offer_recovery() {
+                die(error);
+            }
+        }
         loop {
             if let Err(error) = self.refresh_screen() {
                 die(error);
             }
             if self.should_quit {
+                self.document.remove_swap_file();
+                for buffer in &self.buffers {
+                    buffer.document.remove_swap_file();
+                }
+                if let Some(mut output) = self.terminal.take_output() {
+                    if let Err(error) = self.document.write_to(&mut output) {
+                        die(error);
+                    }
+                }
                 break;
             }
-            if let Err(error) = self.process_keypress() {
+            if let Err(error) = self.wait_for_keypress() {
                 die(error);
             }
         }
     }
-    pub fn default() -> Self {
-        let args: Vec<String> = env::args().collect();
+    // Waits for the next key, doing background work in between; returns early
+    // when that work changed what is on screen.
+    fn wait_for_keypress(&mut self) -> Result<(), std::io::Error> {
+        loop {
+            if let Some(pressed_key) = Terminal::poll_key(TICK)? {
+                return self.process_keypress(pressed_key);
+            }
+            if self.tick()? {
+                return Ok(());
+            }
+        }
+    }
+    fn tick(&mut self) -> Result<bool, std::io::Error> {
+        let mut redraw = self.document.poll_index();
+        for buffer in &mut self.buffers {
+            buffer.document.poll_index();
+        }
+        if self.disk_check_time.elapsed() >= DISK_CHECK_INTERVAL {
+            self.disk_check_time = Instant::now();
+            if self.document.changed_on_disk() {
+                self.resolve_disk_change(false)?;
+                redraw = true;
+            }
+            redraw |= self.check_parked_buffers();
+        }
+        if self.swap_time.elapsed() >= SWAP_INTERVAL && self.write_swap_file() {
+            redraw = true;
+        }
+        Ok(redraw)
+    }
+    // Snapshots the parked buffers that are due and tells when the file of
+    // one changed on disk, as they cannot be asked about until they are
+    // switched to. Returns whether a message was shown.
+    fn check_parked_buffers(&mut self) -> bool {
+        let mut message = None;
+        for buffer in &mut self.buffers {
+            if buffer.swap_time.elapsed() >= SWAP_INTERVAL {
+                if let Err(error) = snapshot(
+                    &buffer.document,
+                    &mut buffer.swap_revision,
+                    &mut buffer.swap_time,
+                ) {
+                    message = Some(format!(
+                        "[{}] Could not write swap file: {}",
+                        style("Error").red(),
+                        error
+                    ));
+                }
+            }
+            if !buffer.changed_on_disk && buffer.document.changed_on_disk() {
+                buffer.changed_on_disk = true;
+                message = Some(format!(
+                    "{} changed on disk; switch to it to reload or keep ours.",
+                    buffer.document.file_name.as_deref().unwrap_or("[No Name]")
+                ));
+            }
+        }
+        if let Some(message) = message {
+            self.status_message = StatusMessage::from(message);
+            return true;
+        }
+        false
+    }
+    // Returns whether an error was shown.
+    fn write_swap_file(&mut self) -> bool {
+        if let Err(error) = snapshot(&self.document, &mut self.swap_revision, &mut self.swap_time) {
+            self.status_message = StatusMessage::from(format!(
+                "[{}] Could not write swap file: {}",
+                style("Error").red(),
+                error
+            ));
+            return true;
+        }
+        false
+    }
+    // Asks whether to reload a file that changed on disk or keep the contents
+    // being edited; returns whether a save may go ahead. Esc keeps ours, but
+    // cancels a save.
+    fn resolve_disk_change(&mut self, saving: bool) -> Result<bool, std::io::Error> {
+        let mut prompt = "The file changed on disk".to_string();
+        loop {
+            let answer = self.prompt(
+                &format!("{}. (R)eload, (K)eep ours, (D)iff: ", prompt),
+                |_, _, _| {},
+            )?;
+            let answer = answer.map(|answer| answer.trim().to_ascii_lowercase());
+            match answer.as_deref() {
+                Some("r") => {
+                    self.status_message = match self.document.reload(None) {
+                        Ok(()) => StatusMessage::from("Reloaded the file.".to_string()),
+                        Err(error) => StatusMessage::from(format!(
+                            "[{}] Could not reload file: {}",
+                            style("Error").red(),
+                            error
+                        )),
+                    };
+                    self.clamp_cursor();
+                    return Ok(false);
+                }
+                Some("d") => {
+                    prompt = match self.document.disk_diff() {
+                        Ok(diff) => diff,
+                        Err(error) => format!("Could not read the file: {}", error),
+                    };
+                }
+                None if saving => {
+                    self.status_message = StatusMessage::from("Save aborted.".to_string());
+                    return Ok(false);
+                }
+                Some("k") | None => {
+                    if self.document.keep_changes() {
+                        return Ok(true);
+                    }
+                    prompt = "Large files can only be reloaded".to_string();
+                }
+                Some(_) => (),
+            }
+        }
+    }
+    fn clamp_cursor(&mut self) {
+        let y = self.cursor_position.y.min(self.document.len());
+        let x = self
+            .document
+            .row(y)
+            .map_or(0, |row| row.get_width_to(row.get_char_index(self.cursor_position.x)));
+        self.cursor_position = Position { x, y };
+        self.scroll();
+    }
+    // Asks what to do with the swap file a crashed session left behind; it
+    // stays on disk until the file is saved or the editor quits.
+    fn offer_recovery(&mut self) -> Result<(), std::io::Error> {
+        let mut prompt = "Found unsaved changes in a swap file".to_string();
+        loop {
+            let answer = self.prompt(
+                &format!("{}. (R)ecover, (D)iff, (X) delete, Esc ignore: ", prompt),
+                |_, _, _| {},
+            )?;
+            let answer = answer.map(|answer| answer.trim().to_ascii_lowercase());
+            match answer.as_deref() {
+                Some("r") => {
+                    self.status_message = match self.document.recover_swap_file() {
+                        Ok(()) => StatusMessage::from("Recovered unsaved changes.".to_string()),
+                        Err(error) => StatusMessage::from(format!(
+                            "[{}] Could not recover swap file: {}",
+                            style("Error").red(),
+                            error
+                        )),
+                    };
+                    return Ok(());
+                }
+                Some("d") => {
+                    prompt = match self.document.swap_file_diff() {
+                        Ok(diff) => diff,
+                        Err(error) => format!("Could not read swap file: {}", error),
+                    };
+                }
+                Some("x") => {
+                    self.document.remove_swap_file();
+                    return Ok(());
+                }
+                None => return Ok(()),
+                Some(_) => (),
+            }
+        }
+    }
+    // Opens every file given on the command line in its own buffer, the first
+    // one being current.
+    pub fn new(args: Args) -> Self {
+        Self::with_terminal(args, || {
+            Terminal::default().expect(&format!("[{}] Failed to initialize terminal", style("Error").red()))
+        })
+    }
+    // The terminal is set up once the files are read, which can mean reading
+    // standard input.
+    fn with_terminal<F: FnOnce() -> Terminal>(args: Args, terminal: F) -> Self {
+        width::set_ambiguous_wide(args.ambiguous_wide);
+        if let Some(tab_width) = args.tab_width {
+            width::set_tab_width(tab_width);
+        }
         let mut initial_status =
-            String::from(format!("[{}]: Ctrl-F = find | Ctrl-S = save | Ctrl-Q = quit", style("Help").cyan()));
-
-        let document = if let Some(file_name) = args.get(1) {
-            let doc = Document::open(file_name);
-            if let Ok(doc) = doc {
-                doc
-            } else {
-                initial_status = format!("[{}] Could not open file: {}",style("Error").red(), file_name);
-                Document::default()
+            String::from(format!("[{}]: Ctrl-F = find | Ctrl-S = save | Ctrl-O = open | Ctrl-Q = quit", style("Help").cyan()));
+        let mut buffers = Vec::new();
+        let mut failed = Vec::new();
+        for file in &args.files {
+            match open_document(&file.name, args.encoding) {
+                Ok(mut document) => {
+                    document.set_readonly(args.readonly);
+                    let mut buffer = Buffer::from(document);
+                    if let Some(line) = file.line {
+                        buffer.cursor_position = target_position(
+                            &mut buffer.document,
+                            line.saturating_sub(1),
+                            file.column.unwrap_or(1).saturating_sub(1),
+                        );
+                    }
+                    buffers.push(buffer);
+                }
+                Err(_) => failed.push(file.name.clone()),
             }
+        }
+        if !failed.is_empty() {
+            initial_status = format!("[{}] Could not open file: {}",style("Error").red(), failed.join(", "));
+        }
+        let buffer = if buffers.is_empty() {
+            Buffer::from(Document::default())
         } else {
-            Document::default()
+            buffers.remove(0)
         };
 
-        Self {
+        let mut editor = Self {
             should_quit: false,
-            terminal: Terminal::default().expect(&format!("[{}] Failed to initialize terminal", style("Error").red())),
-            document,
-            cursor_position: Position::default(),
+            terminal: terminal(),
+            document: buffer.document,
+            cursor_position: buffer.cursor_position,
             offset: Position::default(),
             status_message: StatusMessage::from(initial_status),
             quit_times: QUIT_TIMES,
             highlighted_word: None,
+            swap_revision: 0,
+            swap_time: Instant::now(),
+            disk_check_time: Instant::now(),
+            buffers,
+            current: 0,
+            prompt_history: HashMap::new(),
+            search_options: SearchOptions::default(),
+            prompt_cursor: None,
+            prompt_note: String::new(),
+            current_match: None,
+            results: buffer.results,
+        };
+        editor.scroll();
+        editor
+    }
+
+    fn buffer_count(&self) -> usize {
+        self.buffers.len().saturating_add(1)
+    }
+    // The position in the buffer list of `buffers[parked]`.
+    fn buffer_index(&self, parked: usize) -> usize {
+        if parked < self.current {
+            parked
+        } else {
+            parked.saturating_add(1)
+        }
+    }
+    // Makes `buffer` the current one and returns the state it replaced.
+    fn replace_buffer(&mut self, buffer: Buffer) -> Buffer {
+        Buffer {
+            document: mem::replace(&mut self.document, buffer.document),
+            cursor_position: mem::replace(&mut self.cursor_position, buffer.cursor_position),
+            offset: mem::replace(&mut self.offset, buffer.offset),
+            highlighted_word: mem::replace(&mut self.highlighted_word, buffer.highlighted_word),
+            swap_revision: mem::replace(&mut self.swap_revision, buffer.swap_revision),
+            swap_time: mem::replace(&mut self.swap_time, buffer.swap_time),
+            results: mem::replace(&mut self.results, buffer.results),
+            changed_on_disk: false,
+        }
+    }
+    fn switch_buffer(&mut self, index: usize) -> Result<(), std::io::Error> {
+        if index == self.current || index >= self.buffer_count() {
+            return Ok(());
+        }
+        // A parked buffer does not change, so this is its last chance to be
+        // snapshotted for a while.
+        self.write_swap_file();
+        let previous = self.replace_buffer(Buffer::from(Document::default()));
+        self.buffers.insert(self.current, previous);
+        let buffer = self.buffers.remove(index);
+        self.replace_buffer(buffer);
+        self.current = index;
+        self.scroll();
+        if self.document.revision() == 0 && self.document.has_newer_swap_file() {
+            self.offer_recovery()?;
+        }
+        if self.document.changed_on_disk() {
+            self.resolve_disk_change(false)?;
+        }
+        Ok(())
+    }
+    fn cycle_buffer(&mut self, forward: bool) -> Result<(), std::io::Error> {
+        let count = self.buffer_count();
+        let index = if forward {
+            self.current.saturating_add(1) % count
+        } else {
+            self.current.checked_sub(1).unwrap_or(count.saturating_sub(1))
+        };
+        self.switch_buffer(index)
+    }
+    fn open_buffer(&mut self) -> Result<(), std::io::Error> {
+        if let Some(file_name) = self.prompt_with("Open: ", "", PromptKind::Path, |_, _, _| {})? {
+            self.open_file(&file_name)?;
+        }
+        Ok(())
+    }
+    // Switches to the file if it is already open; returns whether it is the
+    // current buffer now.
+    fn open_file(&mut self, file_name: &str) -> Result<bool, std::io::Error> {
+        let is_open = |document: &Document| document.file_name.as_deref() == Some(file_name);
+        if is_open(&self.document) {
+            return Ok(true);
+        }
+        if let Some(parked) = self.buffers.iter().position(|buffer| is_open(&buffer.document)) {
+            self.switch_buffer(self.buffer_index(parked))?;
+            return Ok(true);
+        }
+        let document = match open_document(file_name, None) {
+            Ok(document) => document,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!(
+                    "[{}] Could not open file {}: {}",
+                    style("Error").red(),
+                    file_name,
+                    error
+                ));
+                return Ok(false);
+            }
+        };
+        self.add_buffer(Buffer::from(document))?;
+        Ok(true)
+    }
+    // Makes `buffer` the current one, after the others. An empty, untouched
+    // buffer is replaced rather than kept around.
+    fn add_buffer(&mut self, buffer: Buffer) -> Result<(), std::io::Error> {
+        let is_scratch = self.document.file_name.is_none()
+            && self.document.is_empty()
+            && !self.document.is_dirty();
+        self.write_swap_file();
+        let previous = self.replace_buffer(buffer);
+        if !is_scratch {
+            self.buffers.insert(self.current, previous);
+            self.current = self.buffers.len();
         }
+        if self.document.has_newer_swap_file() {
+            self.offer_recovery()?;
+        }
+        Ok(())
+    }
+    fn close_buffer(&mut self) -> Result<(), std::io::Error> {
+        if self.document.is_dirty() {
+            let answer =
+                self.prompt("Buffer has unsaved changes. Close anyway? (y/N): ", |_, _, _| {})?;
+            if answer.map_or(true, |answer| !answer.trim().eq_ignore_ascii_case("y")) {
+                return Ok(());
+            }
+        }
+        self.document.remove_swap_file();
+        let next = if self.buffers.is_empty() {
+            Buffer::from(Document::default())
+        } else {
+            self.current = self.current.min(self.buffers.len().saturating_sub(1));
+            self.buffers.remove(self.current)
+        };
+        self.replace_buffer(next);
+        if self.document.changed_on_disk() {
+            self.resolve_disk_change(false)?;
+        }
+        Ok(())
+    }
+    fn list_buffers(&mut self) -> Result<(), std::io::Error> {
+        let describe = |index: usize, document: &Document| {
+            format!(
+                "{}:{}{}",
+                index.saturating_add(1),
+                document.file_name.as_deref().unwrap_or("[No Name]"),
+                if document.is_dirty() { "*" } else { "" }
+            )
+        };
+        let mut names: Vec<String> = self
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(parked, buffer)| describe(self.buffer_index(parked), &buffer.document))
+            .collect();
+        names.insert(self.current, format!("[{}]", describe(self.current, &self.document)));
+        let prompt = format!("{} switch to: ", names.join(" "));
+        if let Some(answer) = self.prompt(&prompt, |_, _, _| {})? {
+            match answer.trim().parse::<usize>() {
+                Ok(number) if number > 0 && number <= self.buffer_count() => {
+                    self.switch_buffer(number.saturating_sub(1))?;
+                }
+                _ => {
+                    self.status_message = StatusMessage::from(format!(
+                        "[{}] No buffer {}",
+                        style("Error").red(),
+                        answer
+                    ));
+                }
+            }
+        }
+        Ok(())
     }
 
     fn refresh_screen(&mut self) -> Result<(), std::io::Error> {
@@ -111,21 +608,29 @@ impl Editor {
             Terminal::clear_screen();
             println!("Goodbye.\r");
         } else {
+            let height = self.terminal.size().height as usize;
+            self.document
+                .load_rows(self.offset.y, self.offset.y.saturating_add(height))?;
             self.document.highlight(
                 &self.highlighted_word,
-                Some(
-                    self.offset
-                        .y
-                        .saturating_add(self.terminal.size().height as usize),
-                ),
+                self.current_match.as_ref(),
+                &(self.offset.y..self.offset.y.saturating_add(height)),
             );
             self.draw_rows();
             self.draw_status_bar();
             self.draw_message_bar();
-            Terminal::cursor_position(&Position {
-                x: self.cursor_position.x.saturating_sub(self.offset.x),
-                y: self.cursor_position.y.saturating_sub(self.offset.y),
-            });
+            if let Some(x) = self.prompt_cursor {
+                let size = self.terminal.size();
+                Terminal::cursor_position(&Position {
+                    x: x.min(size.width.saturating_sub(1) as usize),
+                    y: size.height.saturating_add(1) as usize,
+                });
+            } else {
+                Terminal::cursor_position(&Position {
+                    x: self.cursor_position.x.saturating_sub(self.offset.x),
+                    y: self.cursor_position.y.saturating_sub(self.offset.y),
+                });
+            }
         }
         Terminal::cursor_show();
         Terminal::flush()
@@ -133,22 +638,43 @@ impl Editor {
 
     fn save(&mut self) {
         if self.document.file_name.is_none() {
-            let new_name = self.prompt("Save as: ", |_, _, _| {}).unwrap_or(None);
-            if new_name.is_none() {
+            self.save_as();
+            return;
+        }
+        if self.document.changed_on_disk() && !self.resolve_disk_change(true).unwrap_or(false) {
+            return;
+        }
+        match self.document.save() {
+            Ok(()) => self.status_message = StatusMessage::from(format!("[{}] File save successfully!", style("Success").green())),
+            Err(error) => self.status_message = StatusMessage::from(format!("[{}] Error writing file: {}", style("Error").red(), error)),
+        }
+    }
+    // Saves under a name typed by the user, starting from the current one.
+    fn save_as(&mut self) {
+        let current = self.document.file_name.clone().unwrap_or_default();
+        let file_name = match self.prompt_with("Save as: ", &current, PromptKind::Path, |_, _, _| {}) {
+            Ok(Some(file_name)) => file_name,
+            _ => {
                 self.status_message = StatusMessage::from("Save aborted.".to_string());
                 return;
             }
-            let mut file_name = new_name.unwrap();
-            if !file_name.ends_with(".txt") {
-                file_name.push_str(".txt");
+        };
+        if file_name == current {
+            self.save();
+            return;
+        }
+        if Path::new(&file_name).exists() {
+            let answer = self
+                .prompt(&format!("{} exists. Overwrite? (y/N): ", file_name), |_, _, _| {})
+                .unwrap_or(None);
+            if answer.map_or(true, |answer| !answer.trim().eq_ignore_ascii_case("y")) {
+                self.status_message = StatusMessage::from("Save aborted.".to_string());
+                return;
             }
-            self.document.file_name = Some(file_name);
         }
-
-        if self.document.save().is_ok() {
-            self.status_message = StatusMessage::from(format!("[{}] File save successfully!", style("Success").green()));
-        } else {
-            self.status_message = StatusMessage::from(format!("[{}] Error writing file!", style("Error").red()));
+        match self.document.save_as(file_name) {
+            Ok(()) => self.status_message = StatusMessage::from(format!("[{}] File save successfully!", style("Success").green())),
+            Err(error) => self.status_message = StatusMessage::from(format!("[{}] Error writing file: {}", style("Error").red(), error)),
         }
     }
 
@@ -156,36 +682,35 @@ impl Editor {
         let old_position = self.cursor_position.clone();
         let mut direction = SearchDirection::Forward;
         let query = self
-            .prompt(
-                "Search (ESC to cancel, Arrows to navigate): ",
+            .prompt_with(
+                "Search (Tab/Shift-Tab, Alt-R/C/W): ",
+                "",
+                PromptKind::Search,
                 |editor, key, query| {
                     let mut moved = false;
-                    match key {
-                        KeyEvent {
-                            code: KeyCode::Right | KeyCode::Down | KeyCode::Enter,
-                            modifiers: KeyModifiers::NONE,
-                        } => {
-                            direction = SearchDirection::Forward;
+                    direction = SearchDirection::Forward;
+                    match (key.modifiers, key.code) {
+                        (_, KeyCode::Tab) => {
                             editor.move_cursor(KeyCode::Right);
                             moved = true;
                         }
-                        KeyEvent {
-                            code: KeyCode::Left | KeyCode::Up,
-                            modifiers: KeyModifiers::NONE,
-                        } => direction = SearchDirection::Backward,
-                        _ => direction = SearchDirection::Forward,
+                        (_, KeyCode::BackTab) => direction = SearchDirection::Backward,
+                        _ => editor.toggle_search_option(&key),
                     }
-                    if let Some(position) =
-                        editor
-                            .document
-                            .find(&query, &editor.cursor_position, direction)
-                    {
-                        editor.cursor_position = position;
+                    editor.highlighted_word = editor.search_query(query);
+                    let at = editor.cursor_index();
+                    let found = editor
+                        .highlighted_word
+                        .as_ref()
+                        .and_then(|word| editor.document.find_wrapping(word, &at, direction));
+                    let wrapped = found.as_ref().map(|(_, wrapped)| *wrapped);
+                    if let Some((found, _)) = found {
+                        editor.set_cursor_index(&found.start);
                         editor.scroll();
                     } else if moved {
                         editor.move_cursor(KeyCode::Left);
                     }
-                    editor.highlighted_word = Some(query.to_string());
+                    editor.prompt_note = editor.match_note(wrapped);
                 },
             )
             .unwrap_or(None);
@@ -197,26 +722,425 @@ impl Editor {
         self.highlighted_word = None;
     }
 
-    fn process_keypress(&mut self) -> Result<(), std::io::Error> {
-        let pressed_key = Terminal::read_key()?;
+    // Which match the cursor is on, out of how many, and whether the search
+    // went past the end of the document to find it; `wrapped` is `None` when
+    // nothing was found.
+    fn match_note(&mut self, wrapped: Option<bool>) -> String {
+        let word = if let Some(word) = &self.highlighted_word {
+            word
+        } else {
+            return String::new();
+        };
+        let mut notes = Vec::new();
+        match wrapped {
+            None => notes.push("no matches".to_string()),
+            Some(wrapped) => {
+                if let Some((current, total)) =
+                    self.document.count_matches(word, &self.cursor_index())
+                {
+                    notes.push(format!("match {} of {}", current, total));
+                }
+                if wrapped {
+                    notes.push("search wrapped".to_string());
+                }
+            }
+        }
+        if notes.is_empty() {
+            String::new()
+        } else {
+            format!("  [{}]", notes.join(", "))
+        }
+    }
+
+    fn toggle_search_option(&mut self, key: &KeyEvent) {
+        let options = &mut self.search_options;
+        match (key.modifiers, key.code) {
+            (KeyModifiers::ALT, KeyCode::Char('r')) => options.regex = !options.regex,
+            (KeyModifiers::ALT, KeyCode::Char('c')) => options.ignore_case = !options.ignore_case,
+            (KeyModifiers::ALT, KeyCode::Char('w')) => options.whole_word = !options.whole_word,
+            _ => (),
+        }
+    }
+    fn search_query(&self, text: &str) -> Option<SearchQuery> {
+        if text.is_empty() {
+            None
+        } else {
+            SearchQuery::new(text, self.search_options).ok()
+        }
+    }
+
+    // Replaces matches from the cursor to the end of the document, asking
+    // about each one until told to replace all; the whole replace is undone
+    // in one step.
+    fn replace(&mut self) -> Result<(), std::io::Error> {
+        let text = self.prompt_with(
+            "Replace (Alt-R/C/W for options): ",
+            "",
+            PromptKind::Search,
+            |editor, key, query| {
+                editor.toggle_search_option(&key);
+                editor.highlighted_word = editor.search_query(query);
+            },
+        )?;
+        let query = match text.as_deref().map(|text| self.search_query(text)) {
+            Some(Some(query)) => query,
+            Some(None) => {
+                self.highlighted_word = None;
+                self.status_message = StatusMessage::from(format!(
+                    "[{}] Invalid pattern",
+                    style("Error").red()
+                ));
+                return Ok(());
+            }
+            None => {
+                self.highlighted_word = None;
+                return Ok(());
+            }
+        };
+        let template = self.prompt_with(
+            &format!("Replace {} with: ", query.text()),
+            "",
+            PromptKind::Replacement,
+            |_, _, _| {},
+        )?;
+        let template = if let Some(template) = template {
+            template
+        } else {
+            self.highlighted_word = None;
+            return Ok(());
+        };
+        self.highlighted_word = Some(query.clone());
+        let start = self.cursor_position.clone();
+        let mut at = self.cursor_index();
+        let mut replace_all = false;
+        let mut count = 0_usize;
+        self.document.begin_change();
+        while let Some(range) = self.document.find(&query, &at, SearchDirection::Forward) {
+            let found = range.start.clone();
+            self.set_cursor_index(&found);
+            self.scroll();
+            if !replace_all {
+                self.status_message = StatusMessage::from(
+                    "Replace this match? (y)es, (n)o, (a)ll, (q)uit".to_string(),
+                );
+                self.current_match = Some(range);
+                self.refresh_screen()?;
+                self.current_match = None;
+                match Terminal::read_key()?.code {
+                    KeyCode::Char('y' | 'Y') => (),
+                    KeyCode::Char('a' | 'A') => replace_all = true,
+                    KeyCode::Char('n' | 'N') => {
+                        at = Position {
+                            x: found.x.saturating_add(1),
+                            y: found.y,
+                        };
+                        continue;
+                    }
+                    _ => break,
+                }
+            }
+            if let Some(after) = self.document.replace(&query, &found, &template) {
+                count = count.saturating_add(1);
+                self.set_cursor_index(&after);
+                at = after;
+            } else {
+                at = Position {
+                    x: found.x.saturating_add(1),
+                    y: found.y,
+                };
+            }
+        }
+        if count > 0 {
+            self.document.set_cursors(&start, &self.cursor_position);
+        }
+        self.document.end_change();
+        self.highlighted_word = None;
+        self.scroll();
+        self.status_message = StatusMessage::from(format!("Replaced {} match(es).", count));
+        Ok(())
+    }
+
+    // Searches the files under the current directory and lists the matching
+    // lines in a new read-only buffer, where Enter opens the one under the
+    // cursor.
+    fn find_in_files(&mut self) -> Result<(), std::io::Error> {
+        let text = self.prompt_with(
+            "Find in files (Alt-R/C/W): ",
+            "",
+            PromptKind::Search,
+            |editor, key, _| editor.toggle_search_option(&key),
+        )?;
+        let query = match text.as_deref().map(|text| SearchQuery::new(text, self.search_options)) {
+            Some(Ok(query)) => query,
+            Some(Err(error)) => {
+                self.status_message = StatusMessage::from(format!(
+                    "[{}] Invalid pattern: {}",
+                    style("Error").red(),
+                    error
+                ));
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        self.status_message = StatusMessage::from("Searching...".to_string());
+        self.refresh_screen()?;
+        let (matches, truncated) = grep(Path::new("."), &query);
+        if matches.is_empty() {
+            self.status_message =
+                StatusMessage::from(format!("No matches for {}.", query.text()));
+            return Ok(());
+        }
+        // One row per matching line, so that the rows line up with the
+        // targets; a line break in a file name would otherwise split its row
+        // in two.
+        let rows = matches
+            .iter()
+            .map(|found| {
+                Row::from(
+                    format!(
+                        "{}:{}: {}",
+                        escape_line_breaks(&found.path),
+                        found.line.saturating_add(1),
+                        escape_line_breaks(&found.text)
+                    )
+                    .as_str(),
+                )
+            })
+            .collect();
+        let mut document = Document::from_rows(rows);
+        document.set_readonly(true);
+        let count = matches.len();
+        let mut buffer = Buffer::from(document);
+        buffer.highlighted_word = Some(query.clone());
+        buffer.results = Some(Results {
+            query,
+            targets: matches.into_iter().map(|found| (found.path, found.line)).collect(),
+        });
+        self.add_buffer(buffer)?;
+        self.status_message = StatusMessage::from(format!(
+            "{} match(es){}. Enter opens the one under the cursor.",
+            count,
+            if truncated { ", more not shown" } else { "" }
+        ));
+        Ok(())
+    }
+    // Opens the file of the result under the cursor, on its first match.
+    fn open_result(&mut self) -> Result<(), std::io::Error> {
+        let (query, file_name, line) = match &self.results {
+            Some(results) => match results.targets.get(self.cursor_position.y) {
+                Some((file_name, line)) => (results.query.clone(), file_name.clone(), *line),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        if !self.open_file(&file_name)? {
+            return Ok(());
+        }
+        self.document.load_rows(line, line.saturating_add(1))?;
+        let column = self
+            .document
+            .row(line)
+            .and_then(|row| row.find(&query, 0, SearchDirection::Forward))
+            .unwrap_or(0);
+        self.cursor_position = target_position(&mut self.document, line, column);
+        self.scroll();
+        Ok(())
+    }
+
+    // The active search options, and whether `text` can be searched for
+    // with them.
+    fn search_indicator(&self, text: &str) -> String {
+        if SearchQuery::new(text, self.search_options).is_err() {
+            format!("{}[{}] ", self.search_options, style("invalid pattern").red())
+        } else {
+            self.search_options.to_string()
+        }
+    }
+
+    fn undo_tree(&mut self) {
+        let mut branches = self.document.undo_branches();
+        branches.sort_by_key(|branch| branch.time);
+        let branches: Vec<String> = branches
+            .iter()
+            .rev()
+            .take(5)
+            .map(|branch| format!("#{} {}", branch.state, format_age(branch.time)))
+            .collect();
+        let prompt = format!(
+            "Undo tree at #{} [{}] go to state: ",
+            self.document.undo_state(),
+            branches.join(", ")
+        );
+        let state = self.prompt_with(&prompt, "", PromptKind::Text, |_, _, _| {}).unwrap_or(None);
+        if let Some(state) = state {
+            if let Some(position) = state
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|state| self.document.goto_state(state))
+            {
+                self.cursor_position = position;
+                self.scroll();
+            } else {
+                self.status_message = StatusMessage::from(format!(
+                    "[{}] No undo state {}",
+                    style("Error").red(),
+                    state
+                ));
+            }
+        }
+    }
+
+    fn convert_line_endings(&mut self) {
+        let prompt = format!(
+            "Line endings are {}, convert to (LF/CRLF/CR): ",
+            self.document.line_ending()
+        );
+        let name = self.prompt_with(&prompt, "", PromptKind::Text, |_, _, _| {}).unwrap_or(None);
+        if let Some(name) = name {
+            self.status_message = match LineEnding::from_name(&name) {
+                Some(ending) if self.document.set_line_ending(ending) => {
+                    StatusMessage::from(format!("Converted line endings to {}.", ending.name()))
+                }
+                Some(_) => StatusMessage::from(format!(
+                    "[{}] Line endings of large files cannot be converted",
+                    style("Error").red()
+                )),
+                None => StatusMessage::from(format!(
+                    "[{}] Unknown line ending {}",
+                    style("Error").red(),
+                    name
+                )),
+            };
+        }
+    }
+
+    fn retab(&mut self) {
+        let answer = self
+            .prompt_with("Retab indentation to (tabs/spaces): ", "", PromptKind::Text, |_, _, _| {})
+            .unwrap_or(None);
+        if let Some(answer) = answer {
+            let expand = match answer.trim().to_ascii_lowercase().as_str() {
+                "t" | "tab" | "tabs" => false,
+                "s" | "space" | "spaces" => true,
+                _ => {
+                    self.status_message = StatusMessage::from(format!(
+                        "[{}] Unknown indentation {}",
+                        style("Error").red(),
+                        answer
+                    ));
+                    return;
+                }
+            };
+            self.status_message = match self.document.retab(expand) {
+                Some(count) => StatusMessage::from(format!("Retabbed {} line(s).", count)),
+                None => StatusMessage::from(format!(
+                    "[{}] Large files cannot be retabbed",
+                    style("Error").red()
+                )),
+            };
+            self.clamp_cursor();
+        }
+    }
+
+    // An unmodified file is read again in the chosen encoding, which fixes a
+    // wrong guess; otherwise the encoding is used the next time it is saved.
+    fn change_encoding(&mut self) {
+        let prompt = format!(
+            "Encoding is {}, change to (e.g. UTF-8, GBK, Shift_JIS, Latin1): ",
+            self.document.encoding()
+        );
+        let label = self.prompt_with(&prompt, "", PromptKind::Text, |_, _, _| {}).unwrap_or(None);
+        let label = if let Some(label) = label {
+            label
+        } else {
+            return;
+        };
+        let encoding = if let Some(encoding) = TextEncoding::from_label(&label) {
+            encoding
+        } else {
+            self.status_message = StatusMessage::from(format!(
+                "[{}] Unknown encoding {}",
+                style("Error").red(),
+                label
+            ));
+            return;
+        };
+        if !self.document.is_dirty() && self.document.file_name.is_some() {
+            self.status_message = match self.document.reload(Some(encoding)) {
+                Ok(()) => StatusMessage::from(format!("Reloaded as {}.", encoding.name())),
+                Err(error) => StatusMessage::from(format!(
+                    "[{}] Could not reload file: {}",
+                    style("Error").red(),
+                    error
+                )),
+            };
+            self.cursor_position = Position::default();
+            self.offset = Position::default();
+        } else if self.document.set_encoding(encoding) {
+            self.status_message =
+                StatusMessage::from(format!("The file will be saved as {}.", encoding.name()));
+        } else {
+            self.status_message = StatusMessage::from(format!(
+                "[{}] The encoding of large files cannot be changed",
+                style("Error").red()
+            ));
+        }
+    }
+
+    fn process_keypress(&mut self, pressed_key: KeyEvent) -> Result<(), std::io::Error> {
+        if self.results.is_some() && pressed_key.code == KeyCode::Enter {
+            return self.open_result();
+        }
+        if self.document.is_readonly() && is_edit(&pressed_key) {
+            self.status_message = StatusMessage::from(format!(
+                "[{}] The buffer is read-only",
+                style("Error").red()
+            ));
+            return Ok(());
+        }
+        let cursor_before = self.cursor_position.clone();
+        let revision = self.document.revision();
         match (pressed_key.modifiers, pressed_key.code) {
             (KeyModifiers::CONTROL, KeyCode::Char('z')) => {
-                println!("Undo\r");  // 
-                if self.document.undo() {
-                    self.refresh_screen()?;
+                if let Some(position) = self.document.undo() {
+                    self.cursor_position = position;
                 }
             }
             (KeyModifiers::CONTROL, KeyCode::Char('y')) => {
-                println!("Redo\r");  // 
-                if self.document.redo() {
-                    self.refresh_screen()?;
+                if let Some(position) = self.document.redo() {
+                    self.cursor_position = position;
+                }
+            }
+            (KeyModifiers::ALT, KeyCode::Char('z')) => {
+                if let Some(position) = self.document.earlier() {
+                    self.cursor_position = position;
                 }
             }
+            (KeyModifiers::ALT, KeyCode::Char('y')) => {
+                if let Some(position) = self.document.later() {
+                    self.cursor_position = position;
+                }
+            }
+            (KeyModifiers::CONTROL, KeyCode::Char('t')) => self.undo_tree(),
+            (KeyModifiers::CONTROL, KeyCode::Char('e')) => self.convert_line_endings(),
+            (KeyModifiers::ALT, KeyCode::Char('e')) => self.change_encoding(),
+            (KeyModifiers::ALT, KeyCode::Char('t')) => self.retab(),
+            (KeyModifiers::CONTROL, KeyCode::Char('o')) => self.open_buffer()?,
+            (KeyModifiers::CONTROL, KeyCode::Char('n')) => self.cycle_buffer(true)?,
+            (KeyModifiers::CONTROL, KeyCode::Char('p')) => self.cycle_buffer(false)?,
+            (KeyModifiers::CONTROL, KeyCode::Char('b')) => self.list_buffers()?,
+            (KeyModifiers::CONTROL, KeyCode::Char('w')) => self.close_buffer()?,
             (KeyModifiers::CONTROL, KeyCode::Char('q')) | (_, KeyCode::Esc) => {
-                if self.quit_times > 0 && self.document.is_dirty() {
+                // As a filter, an unnamed current buffer is written to the
+                // output on quit, so its changes are not lost.
+                let written = self.terminal.has_output() && self.document.file_name.is_none();
+                let dirty = self.buffers.iter().filter(|buffer| buffer.document.is_dirty()).count()
+                    + usize::from(self.document.is_dirty() && !written);
+                if self.quit_times > 0 && dirty > 0 {
                     self.status_message = StatusMessage::from(format!(
-                        "[{}] File has unsaved changes. Press Ctrl-Q {} more times to quit.",
-                        style("WARNING").red(),style(self.quit_times).cyan()
+                        "[{}] {} file(s) have unsaved changes. Press Ctrl-Q {} more times to quit.",
+                        style("WARNING").red(),dirty,style(self.quit_times).cyan()
                     ));
                     self.quit_times -= 1;
                     return Ok(());
@@ -224,58 +1148,72 @@ impl Editor {
                 self.should_quit = true
             }
             (KeyModifiers::CONTROL, KeyCode::Char('s')) => self.save(),
+            (KeyModifiers::ALT, KeyCode::Char('s')) => self.save_as(),
             (KeyModifiers::CONTROL, KeyCode::Char('f')) => self.search(),
+            (KeyModifiers::CONTROL, KeyCode::Char('r')) => self.replace()?,
+            (KeyModifiers::CONTROL, KeyCode::Char('g')) => self.find_in_files()?,
             (_, KeyCode::Enter) => {
-                self.document.insert(&self.cursor_position, '\n');
+                self.document.insert(&self.cursor_index(), '\n');
                 self.move_cursor(KeyCode::Right);
             },
             (_, KeyCode::Char(c)) => {
-                self.document.insert(&self.cursor_position, c);
+                let at = self.cursor_index();
+                let len = self.document.row(at.y).map_or(0, Row::len);
+                self.document.insert(&at, c);
                 if self.cursor_position.x >= MAX_LINE_LEN {
-                    self.document.insert(&self.cursor_position, '\n');
+                    self.document.insert(&at, '\n');
                     self.cursor_position.x = 0;
                     self.cursor_position.y += 1;
                 } else {
-                    let width = if let Some(row) = self.document.row(self.cursor_position.y) {
-                        row.get_char_width(c)
+                    // A combining mark joins the grapheme before it rather
+                    // than starting one.
+                    let x = if self.document.row(at.y).map_or(0, Row::len) > len {
+                        at.x.saturating_add(1)
                     } else {
-                        1
+                        at.x
                     };
-                    self.cursor_position.x += width;
+                    self.set_cursor_index(&Position { x, y: at.y });
+                }
+            }
+            (_, KeyCode::Tab) => {
+                let indent = if self.document.expand_tab() {
+                    let tab_width = width::tab_width();
+                    " ".repeat(tab_width.saturating_sub(self.cursor_position.x % tab_width))
+                } else {
+                    "\t".to_string()
+                };
+                let mut at = self.cursor_index();
+                for c in indent.chars() {
+                    self.document.insert(&at, c);
+                    at.x = at.x.saturating_add(1);
                 }
+                self.set_cursor_index(&at);
             }
             (_, KeyCode::Delete) => {
-                if let Some(row) = self.document.row(self.cursor_position.y) {
-                    if self.cursor_position.x < row.len() {
-                        if let Some(c) = row.get_char(self.cursor_position.x) {
-                            let char_width = row.get_char_width(c);
-                            self.document.delete(&self.cursor_position);
-                        }
-                    }
+                let at = self.cursor_index();
+                if at.x < self.document.row(at.y).map_or(0, Row::len) {
+                    self.document.delete(&at);
                 }
             },
             (_, KeyCode::Backspace) => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                    if self.cursor_position.x > 0 {
-                        if let Some(row) = self.document.row(self.cursor_position.y) {
-                            let char_index = row.get_char_index(self.cursor_position.x);
-                            if char_index > 0 {
-                                if let Some(c) = row.get_char(char_index - 1) {
-                                    self.cursor_position.x -= row.get_char_width(c);
-                                    self.document.delete(&Position {
-                                        x: char_index - 1,
-                                        y: self.cursor_position.y,
-                                    });
-                                }
-                            }
-                        }
-                    } else if self.cursor_position.y > 0 {
-                        self.cursor_position.y -= 1;
-                        if let Some(row) = self.document.row(self.cursor_position.y) {
-                            self.cursor_position.x = row.get_width_to(row.len());
-                            self.document.delete(&self.cursor_position);
-                        }
-                    }
+                let at = self.cursor_index();
+                let previous = if at.x > 0 {
+                    Some(Position {
+                        x: at.x.saturating_sub(1),
+                        y: at.y,
+                    })
+                } else if at.y > 0 {
+                    let y = at.y.saturating_sub(1);
+                    Some(Position {
+                        x: self.document.row(y).map_or(0, Row::len),
+                        y,
+                    })
+                } else {
+                    None
+                };
+                if let Some(previous) = previous {
+                    self.document.delete(&previous);
+                    self.set_cursor_index(&previous);
                 }
             },
             (_, KeyCode::Up)
@@ -289,6 +1227,9 @@ impl Editor {
             
             _ => (),
         }
+        if self.document.revision() != revision {
+            self.document.set_cursors(&cursor_before, &self.cursor_position);
+        }
         self.scroll();
         if self.quit_times < QUIT_TIMES {
             self.quit_times = QUIT_TIMES;
@@ -297,10 +1238,26 @@ impl Editor {
         Ok(())
     }
 
+    // The cursor counted in graphemes, as the document counts positions,
+    // rather than in screen columns.
+    fn cursor_index(&self) -> Position {
+        let Position { x, y } = self.cursor_position;
+        let x = self.document.row(y).map_or(0, |row| row.get_char_index(x));
+        Position { x, y }
+    }
+    fn set_cursor_index(&mut self, at: &Position) {
+        self.cursor_position = target_position(&mut self.document, at.y, at.x);
+    }
     fn scroll(&mut self) {
         let Position { x, y } = self.cursor_position;
         let width = self.terminal.size().width as usize;
         let height = self.terminal.size().height as usize;
+        // The whole of a wide character under the cursor is kept on screen.
+        let cursor_end = x.saturating_add(
+            self.document
+                .row(y)
+                .map_or(1, |row| row.get_width_at(row.get_char_index(x))),
+        );
         let offset = &mut self.offset;
         if y < offset.y {
             offset.y = y;
@@ -309,15 +1266,15 @@ impl Editor {
         }
         if x < offset.x {
             offset.x = x;
-        } else if x >= offset.x.saturating_add(width) {
-            offset.x = x.saturating_sub(width).saturating_add(1);
+        } else if cursor_end > offset.x.saturating_add(width) {
+            offset.x = cursor_end.saturating_sub(width).min(x);
         }
     }
     fn move_cursor(&mut self, key: KeyCode) {
         let terminal_height = self.terminal.size().height as usize;
         let Position { mut y, mut x } = self.cursor_position;
         let height = self.document.len();
-        let mut width = if let Some(row) = self.document.row(y) {
+        let width = if let Some(row) = self.document.row(y) {
             row.get_width_to(row.len())
         } else {
             0
@@ -334,12 +1291,7 @@ impl Editor {
             KeyCode::Left => {
                 if x > 0 {
                     if let Some(row) = self.document.row(y) {
-                        let char_index = row.get_char_index(x);
-                        if char_index > 0 {
-                            if let Some(c) = row.get_char(char_index - 1) {
-                                x -= row.get_char_width(c);
-                            }
-                        }
+                        x = row.get_width_to(row.get_char_index(x).saturating_sub(1));
                     }
                 } else if y > 0 {
                     y -= 1;
@@ -354,9 +1306,7 @@ impl Editor {
                 if let Some(row) = self.document.row(y) {
                     let char_index = row.get_char_index(x);
                     if char_index < row.len() {
-                        if let Some(c) = row.get_char(char_index) {
-                            x += row.get_char_width(c);
-                        }
+                        x = row.get_width_to(char_index.saturating_add(1));
                     } else if y < height {
                         y += 1;
                         x = 0;
@@ -386,14 +1336,14 @@ impl Editor {
             KeyCode::End => x = width,
             _ => (),
         }
-        width = if let Some(row) = self.document.row(y) {
-            row.get_width_to(row.len())
+        self.document.load_rows(y, y.saturating_add(1)).ok();
+        // Moving up or down can land inside a wide character, so the cursor
+        // goes back to where it starts.
+        x = if let Some(row) = self.document.row(y) {
+            row.get_width_to(row.get_char_index(x))
         } else {
             0
         };
-        if x > width {
-            x = width;
-        }
 
         self.cursor_position = Position { x, y }
     }
@@ -460,29 +1410,49 @@ impl Editor {
             ""
         };
 
-        let mut file_name = "[No Name]".to_string();
+        let readonly_indicator = if self.document.is_readonly() {
+            " (read-only)"
+        } else {
+            ""
+        };
+
+        let indexing_indicator = if self.document.is_indexing() {
+            " (indexing)"
+        } else {
+            ""
+        };
+
+        let mut file_name = if self.results.is_some() {
+            "[Results]".to_string()
+        } else {
+            "[No Name]".to_string()
+        };
         if let Some(name) = &self.document.file_name {
-            file_name = name.clone();
-            file_name.truncate(20);
+            file_name = truncate_str(name, 20, "").to_string();
         }
         status = format!(
-            "{} - {} lines{}",
+            "[{}/{}] {} - {} lines{}{}{}",
+            self.current.saturating_add(1),
+            self.buffer_count(),
             file_name,
             self.document.len(),
+            readonly_indicator,
+            indexing_indicator,
             modified_indicator
         );
 
         let line_indicator = format!(
-            "{} | {}/{}",
+            "{} | {} | {} | {}/{}",
             self.document.file_type(),
+            self.document.encoding(),
+            self.document.line_ending(),
             self.cursor_position.y.saturating_add(1),
             self.document.len()
         );
-        #[allow(clippy::integer_arithmetic)]
-        let len = status.len() + line_indicator.len();
+        let len = measure_text_width(&status).saturating_add(measure_text_width(&line_indicator));
         status.push_str(&" ".repeat(width.saturating_sub(len)));
         status = format!("{}{}", status, line_indicator);
-        status.truncate(width);
+        status = truncate_str(&status, width, "").to_string();
         Terminal::set_bg_color(STATUS_BG_COLOR);
         Terminal::set_fg_color(STATUS_FG_COLOR);
         println!("{}\r", status);
@@ -494,56 +1464,326 @@ impl Editor {
         Terminal::clear_current_line();
         let message = &self.status_message;
         if Instant::now() - message.time < Duration::new(5, 0) {
-            let mut text = message.text.clone();
-            text.truncate(self.terminal.size().width as usize);
-            print!("{}", text);
+            print!("{}", truncate_str(&message.text, self.terminal.size().width as usize, ""));
         }
     }
-    fn prompt<C>(&mut self, prompt: &str, mut callback: C) -> Result<Option<String>, std::io::Error>
+    fn prompt<C>(&mut self, prompt: &str, callback: C) -> Result<Option<String>, std::io::Error>
 where
     C: FnMut(&mut Self, KeyEvent, &String),
 {
-    let mut result = String::new();
+    self.prompt_with(prompt, "", PromptKind::Answer, callback)
+}
+    // Like `prompt`, with `initial` already typed. The text can be edited
+    // like a line in a shell, Up and Down recall earlier answers of the same
+    // kind, and path prompts complete the typed path on Tab, cycling through
+    // the candidates on further Tabs.
+    fn prompt_with<C>(
+        &mut self,
+        prompt: &str,
+        initial: &str,
+        kind: PromptKind,
+        mut callback: C,
+    ) -> Result<Option<String>, std::io::Error>
+where
+    C: FnMut(&mut Self, KeyEvent, &String),
+{
+    let mut input = LineInput::from(initial);
+    let mut completion: Option<Completion> = None;
+    let mut hint = String::new();
+    // The history entry shown, and what was typed before going back to it.
+    let mut recalled: Option<usize> = None;
+    let mut draft = String::new();
+    let mut pending = VecDeque::new();
+    let mut cancelled = false;
     loop {
-        self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
-        self.refresh_screen()?;
-        let key = Terminal::read_key()?;
-        match key {
-            KeyEvent {
-                code: KeyCode::Backspace,
-                ..
-            } => result.truncate(result.len().saturating_sub(1)),
-            KeyEvent {
-                code: KeyCode::Enter,
-                ..
-            } => break,
-            KeyEvent {
-                code: KeyCode::Char(c),
-                modifiers: KeyModifiers::NONE,
-            } => {
-                if !c.is_control() {
-                    result.push(c);
+        if pending.is_empty() {
+            let prompt = if kind == PromptKind::Search {
+                format!("{}{}", self.search_indicator(input.text()), prompt)
+            } else {
+                prompt.to_string()
+            };
+            self.status_message = StatusMessage::from(format!(
+                "{}{}{}{}",
+                prompt,
+                input.text(),
+                hint,
+                self.prompt_note
+            ));
+            self.prompt_cursor =
+                Some(measure_text_width(&prompt).saturating_add(input.cursor_width()));
+            self.refresh_screen()?;
+        }
+        let key = if let Some(key) = pending.pop_front() {
+            key
+        } else {
+            let key = Terminal::read_key()?;
+            // Pasted text arrives as a burst of keys; reading it all at once
+            // keeps a line break in it from ending the prompt.
+            if let KeyCode::Char(_) = key.code {
+                while let Some(next) = Terminal::poll_key(Duration::ZERO)? {
+                    pending.push_back(next);
                 }
             }
-            KeyEvent {
-                code: KeyCode::Esc,
-                ..
-            } => {
-                result.truncate(0);
+            key
+        };
+        hint.clear();
+        if kind == PromptKind::Path && matches!(key.code, KeyCode::Tab | KeyCode::BackTab) {
+            if let Some(completion) = completion.as_mut() {
+                input.set(completion.cycle(key.code == KeyCode::Tab));
+                hint = completion.hint();
+            } else {
+                let candidates = complete_path(input.text());
+                match candidates.len() {
+                    0 => hint = " (no match)".to_string(),
+                    1 => input.set(candidates.first().map_or("", String::as_str)),
+                    _ => {
+                        let prefix = common_prefix(&candidates);
+                        let next = Completion {
+                            start: input
+                                .text()
+                                .rfind(std::path::is_separator)
+                                .map_or(0, |index| index.saturating_add(1)),
+                            candidates,
+                            selected: None,
+                        };
+                        if prefix.len() > input.text().len() {
+                            input.set(&prefix);
+                        }
+                        hint = next.hint();
+                        completion = Some(next);
+                    }
+                }
+            }
+            callback(self, key, &input.text().to_string());
+            continue;
+        }
+        completion = None;
+        match (key.modifiers, key.code) {
+            (_, KeyCode::Enter) if !pending.is_empty() => input.insert(" "),
+            (_, KeyCode::Enter) => break,
+            (_, KeyCode::Esc) => {
+                input.set("");
+                cancelled = true;
                 break;
             }
+            (KeyModifiers::NONE | KeyModifiers::SHIFT, KeyCode::Char(c)) => {
+                if !c.is_control() {
+                    input.insert(c.encode_utf8(&mut [0; 4]));
+                }
+            }
+            (KeyModifiers::ALT, KeyCode::Backspace) | (KeyModifiers::CONTROL, KeyCode::Char('w')) => {
+                input.delete_word();
+            }
+            (_, KeyCode::Backspace) => input.backspace(),
+            (_, KeyCode::Delete) | (KeyModifiers::CONTROL, KeyCode::Char('d')) => input.delete(),
+            (KeyModifiers::CONTROL, KeyCode::Left) | (KeyModifiers::ALT, KeyCode::Char('b')) => {
+                input.word_left();
+            }
+            (KeyModifiers::CONTROL, KeyCode::Right) | (KeyModifiers::ALT, KeyCode::Char('f')) => {
+                input.word_right();
+            }
+            (_, KeyCode::Left) | (KeyModifiers::CONTROL, KeyCode::Char('b')) => input.left(),
+            (_, KeyCode::Right) | (KeyModifiers::CONTROL, KeyCode::Char('f')) => input.right(),
+            (_, KeyCode::Home) | (KeyModifiers::CONTROL, KeyCode::Char('a')) => input.home(),
+            (_, KeyCode::End) | (KeyModifiers::CONTROL, KeyCode::Char('e')) => input.end(),
+            (KeyModifiers::CONTROL, KeyCode::Char('u')) => input.delete_to_start(),
+            (KeyModifiers::CONTROL, KeyCode::Char('k')) => input.delete_to_end(),
+            (_, KeyCode::Up | KeyCode::Down) if kind != PromptKind::Answer => {
+                let history = self.prompt_history.entry(kind).or_default();
+                let next = match (key.code, recalled) {
+                    (KeyCode::Up, None) => history.len().checked_sub(1),
+                    (KeyCode::Up, Some(index)) => Some(index.saturating_sub(1)),
+                    (_, Some(index)) => {
+                        Some(index.saturating_add(1)).filter(|index| *index < history.len())
+                    }
+                    (_, None) => None,
+                };
+                if recalled.is_none() {
+                    draft = input.text().to_string();
+                }
+                match next.and_then(|index| history.get(index)) {
+                    Some(entry) => input.set(entry),
+                    None if recalled.is_some() => input.set(&draft),
+                    None => (),
+                }
+                recalled = next;
+            }
             _ => (),
         }
-        callback(self, key, &result);
+        callback(self, key, &input.text().to_string());
     }
+    self.prompt_cursor = None;
+    self.prompt_note.clear();
     self.status_message = StatusMessage::from(String::new());
-    if result.is_empty() {
+    if cancelled || (input.text().is_empty() && kind != PromptKind::Replacement) {
         return Ok(None);
     }
-    Ok(Some(result))
+    if kind != PromptKind::Answer && !input.text().is_empty() {
+        self.prompt_history.entry(kind).or_default().push(input.text());
+    }
+    Ok(Some(input.text().to_string()))
+}
+}
+// Opens a file with the settings taken from the environment.
+fn open_document(
+    file_name: &str,
+    encoding: Option<TextEncoding>,
+) -> Result<Document, std::io::Error> {
+    let mut document = if file_name == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        Document::from_bytes(&bytes, encoding)
+    } else {
+        Document::open_with_encoding(file_name, encoding)?
+    };
+    if env::var_os("HECTO_UNDOFILE").is_some() {
+        document.set_persistent_undo(true);
+    }
+    if env::var_os("HECTO_BACKUP").is_some() {
+        document.set_backup(true);
+    }
+    Ok(document)
+}
+
+// Shows `\r` and `\n` as escapes, as the terminal would act on them.
+fn escape_line_breaks(text: &str) -> String {
+    text.replace('\r', "\\r").replace('\n', "\\n")
+}
+
+// Writes a swap file for a dirty document that changed since its last
+// snapshot.
+fn snapshot(
+    document: &Document,
+    swap_revision: &mut usize,
+    swap_time: &mut Instant,
+) -> Result<(), std::io::Error> {
+    let revision = document.revision();
+    if !document.is_dirty() || revision == *swap_revision {
+        return Ok(());
+    }
+    *swap_revision = revision;
+    *swap_time = Instant::now();
+    document.write_swap_file()
 }
+
+// Where the cursor goes for a line and a column counted in graphemes, both
+// from 0 and clamped to the document.
+fn target_position(document: &mut Document, line: usize, column: usize) -> Position {
+    let y = line.min(document.len());
+    document.load_rows(y, y.saturating_add(1)).ok();
+    let x = document
+        .row(y)
+        .map_or(0, |row| row.get_width_to(column.min(row.len())));
+    Position { x, y }
 }
+
+// Keys that change the document, refused in read-only buffers. Any other
+// letter is typed or is an undo command, as in `process_keypress`.
+fn is_edit(key: &KeyEvent) -> bool {
+    match (key.modifiers, key.code) {
+        (KeyModifiers::CONTROL, KeyCode::Char('q' | 's' | 'f' | 'g' | 'o' | 'n' | 'p' | 'b' | 'w'))
+        | (KeyModifiers::ALT, KeyCode::Char('e' | 's')) => false,
+        (
+            _,
+            KeyCode::Char(_) | KeyCode::Enter | KeyCode::Tab | KeyCode::Delete | KeyCode::Backspace,
+        ) => true,
+        _ => false,
+    }
+}
+
+#[allow(clippy::integer_arithmetic, clippy::integer_division)]
+fn format_age(time: SystemTime) -> String {
+    let seconds = time.elapsed().map_or(0, |elapsed| elapsed.as_secs());
+    if seconds < 60 {
+        format!("{}s ago", seconds)
+    } else if seconds < 3600 {
+        format!("{}m ago", seconds / 60)
+    } else if seconds < 86400 {
+        format!("{}h ago", seconds / 3600)
+    } else {
+        format!("{}d ago", seconds / 86400)
+    }
+}
+
 fn die(e: std::io::Error) {
     Terminal::clear_screen();
     std::panic::panic_any(e);
 }
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::FileArg;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hecto-{}-{}.txt", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn editor(paths: &[&PathBuf]) -> Editor {
+        let files = paths
+            .iter()
+            .map(|path| FileArg {
+                name: path.to_string_lossy().to_string(),
+                line: None,
+                column: None,
+            })
+            .collect();
+        Editor::with_terminal(Args { files, ..Args::default() }, || Terminal::detached(80, 24))
+    }
+
+    fn press(editor: &mut Editor, modifiers: KeyModifiers, code: KeyCode) {
+        editor.process_keypress(KeyEvent::new(code, modifiers)).unwrap();
+    }
+
+    fn cursor(editor: &Editor) -> (usize, usize) {
+        (editor.cursor_position.x, editor.cursor_position.y)
+    }
+
+    #[test]
+    fn test_undo_restores_cursor() {
+        let path = temp_file("undo-cursor", "hello world\n");
+        let mut editor = editor(&[&path]);
+        press(&mut editor, KeyModifiers::NONE, KeyCode::End);
+        press(&mut editor, KeyModifiers::NONE, KeyCode::Backspace);
+        assert_eq!(cursor(&editor), (10, 0));
+        press(&mut editor, KeyModifiers::NONE, KeyCode::Home);
+        press(&mut editor, KeyModifiers::CONTROL, KeyCode::Char('z'));
+        assert_eq!(editor.document.row(0).map(Row::as_str), Some("hello world"));
+        assert_eq!(cursor(&editor), (11, 0));
+        press(&mut editor, KeyModifiers::NONE, KeyCode::Home);
+        press(&mut editor, KeyModifiers::CONTROL, KeyCode::Char('y'));
+        assert_eq!(editor.document.row(0).map(Row::as_str), Some("hello worl"));
+        assert_eq!(cursor(&editor), (10, 0));
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_buffers() {
+        let first = temp_file("buffer-first", "first\n");
+        let second = temp_file("buffer-second", "second\n");
+        let mut editor = editor(&[&first, &second]);
+        assert_eq!(editor.buffer_count(), 2);
+        press(&mut editor, KeyModifiers::NONE, KeyCode::End);
+        press(&mut editor, KeyModifiers::CONTROL, KeyCode::Char('n'));
+        assert_eq!(editor.document.row(0).map(Row::as_str), Some("second"));
+        assert_eq!(cursor(&editor), (0, 0));
+        press(&mut editor, KeyModifiers::NONE, KeyCode::Char('x'));
+        press(&mut editor, KeyModifiers::CONTROL, KeyCode::Char('p'));
+        assert_eq!(editor.document.row(0).map(Row::as_str), Some("first"));
+        assert_eq!(cursor(&editor), (5, 0));
+        assert!(editor.open_file(&second.to_string_lossy()).unwrap());
+        assert_eq!(editor.current, 1);
+        assert_eq!(editor.document.row(0).map(Row::as_str), Some("xsecond"));
+        press(&mut editor, KeyModifiers::CONTROL, KeyCode::Char('n'));
+        press(&mut editor, KeyModifiers::CONTROL, KeyCode::Char('w'));
+        assert_eq!(editor.buffer_count(), 1);
+        assert_eq!(editor.document.row(0).map(Row::as_str), Some("xsecond"));
+        editor.document.remove_swap_file();
+        fs::remove_file(first).ok();
+        fs::remove_file(second).ok();
+    }
+}
@@ -0,0 +1,168 @@
+use encoding_rs::{Encoding, GB18030, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct TextEncoding {
+    encoding: &'static Encoding,
+    bom: bool,
+}
+
+impl Default for TextEncoding {
+    fn default() -> Self {
+        Self {
+            encoding: UTF_8,
+            bom: false,
+        }
+    }
+}
+
+impl TextEncoding {
+    // Guesses the encoding of a file from its first bytes: a byte order mark
+    // wins, then valid UTF-8, then Shift-JIS if it decodes to text with kana,
+    // then GB18030 (a superset of GBK) if it decodes to mostly Chinese, and
+    // Latin-1 as the fallback that accepts any bytes.
+    pub fn detect(bytes: &[u8]) -> Self {
+        if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+            return Self {
+                encoding,
+                bom: true,
+            };
+        }
+        if std::str::from_utf8(bytes).is_ok() {
+            return Self::default();
+        }
+        let non_ascii = |text: &str| text.chars().filter(|c| !c.is_ascii()).count();
+        if let Some(text) = SHIFT_JIS.decode_without_bom_handling_and_without_replacement(bytes) {
+            let kana = text
+                .chars()
+                .filter(|c| ('\u{3040}'..='\u{30ff}').contains(c))
+                .count();
+            if kana.saturating_mul(10) >= non_ascii(&text) {
+                return Self::from(SHIFT_JIS);
+            }
+        }
+        if let Some(text) = GB18030.decode_without_bom_handling_and_without_replacement(bytes) {
+            let hanzi = text
+                .chars()
+                .filter(|c| {
+                    ('\u{4e00}'..='\u{9fff}').contains(c)
+                        || ('\u{3000}'..='\u{303f}').contains(c)
+                        || ('\u{ff00}'..='\u{ffef}').contains(c)
+                })
+                .count();
+            if hanzi.saturating_mul(2) >= non_ascii(&text) {
+                return Self::from(GB18030);
+            }
+        }
+        Self::from(WINDOWS_1252)
+    }
+    // Accepts any WHATWG label ("gbk", "sjis", "latin1", ...), with a "-bom"
+    // suffix to write a byte order mark.
+    pub fn from_label(label: &str) -> Option<Self> {
+        let label = label.trim().to_ascii_lowercase();
+        let (label, bom) = match label.strip_suffix("-bom") {
+            Some(label) => (label, true),
+            None => (label.as_str(), false),
+        };
+        let encoding = Encoding::for_label(label.as_bytes())?;
+        let bom = bom || encoding == UTF_16LE || encoding == UTF_16BE;
+        if bom && encoding != UTF_8 && encoding != UTF_16LE && encoding != UTF_16BE {
+            return None;
+        }
+        Some(Self { encoding, bom })
+    }
+    pub fn name(self) -> String {
+        let name = if self.encoding == WINDOWS_1252 {
+            "Latin-1"
+        } else {
+            self.encoding.name()
+        };
+        if self.bom && self.encoding == UTF_8 {
+            format!("{} BOM", name)
+        } else {
+            name.to_string()
+        }
+    }
+    pub fn bom(self) -> &'static [u8] {
+        if !self.bom {
+            &[]
+        } else if self.encoding == UTF_16LE {
+            b"\xff\xfe"
+        } else if self.encoding == UTF_16BE {
+            b"\xfe\xff"
+        } else {
+            b"\xef\xbb\xbf"
+        }
+    }
+    // Whether lines can be found by looking for `\n` bytes.
+    pub fn is_ascii_compatible(self) -> bool {
+        self.encoding.is_ascii_compatible()
+    }
+    // Decodes bytes that do not start with a byte order mark.
+    pub fn decode(self, bytes: &[u8]) -> Cow<'_, str> {
+        self.encoding.decode_without_bom_handling(bytes).0
+    }
+    // Fails instead of writing a replacement when the encoding has no
+    // representation for a character.
+    pub fn encode(self, text: &str, bytes: &mut Vec<u8>) -> Result<(), Error> {
+        if self.encoding == UTF_16LE {
+            bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        } else if self.encoding == UTF_16BE {
+            bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        } else {
+            let (encoded, _, had_errors) = self.encoding.encode(text);
+            if had_errors {
+                let unmappable = text
+                    .chars()
+                    .find(|c| self.encoding.encode(&c.to_string()).2)
+                    .unwrap_or_default();
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("'{}' cannot be saved as {}", unmappable, self.name()),
+                ));
+            }
+            bytes.extend_from_slice(&encoded);
+        }
+        Ok(())
+    }
+}
+
+impl From<&'static Encoding> for TextEncoding {
+    fn from(encoding: &'static Encoding) -> Self {
+        Self {
+            encoding,
+            bom: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let detect = |text: &str, encoding: &'static Encoding| {
+            TextEncoding::detect(&encoding.encode(text).0).name()
+        };
+        assert_eq!(detect("plain ascii\n", UTF_8), "UTF-8");
+        assert_eq!(detect("中文文本，编码测试。\n", GB18030), "gb18030");
+        assert_eq!(detect("日本語のテキストです。\n", SHIFT_JIS), "Shift_JIS");
+        assert_eq!(detect("Café, naïve façade.\n", WINDOWS_1252), "Latin-1");
+        assert_eq!(TextEncoding::detect(b"\xef\xbb\xbfbom").name(), "UTF-8 BOM");
+        assert_eq!(TextEncoding::detect(b"\xff\xfeb\0").name(), "UTF-16LE");
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let gbk = TextEncoding::from_label("gbk").unwrap();
+        let mut bytes = Vec::new();
+        gbk.encode("中文", &mut bytes).unwrap();
+        assert_eq!(gbk.decode(&bytes), "中文");
+        let latin1 = TextEncoding::from_label("latin1").unwrap();
+        assert!(latin1.encode("中文", &mut Vec::new()).is_err());
+        let utf16 = TextEncoding::from_label("utf-16le").unwrap();
+        assert_eq!(utf16.bom(), b"\xff\xfe");
+    }
+}
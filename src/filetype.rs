@@ -1,6 +1,10 @@
+use std::path::Path;
+
 pub struct FileType {
     name: String,
     hl_opts: HighlightingOptions,
+    // Whether the Tab key indents with spaces rather than a tab.
+    expand_tab: bool,
 }
 
 #[derive(Default)]
@@ -19,6 +23,7 @@ impl Default for FileType {
         Self {
             name: String::from("No filetype"),
             hl_opts: HighlightingOptions::default(),
+            expand_tab: false,
         }
     }
 }
@@ -30,6 +35,9 @@ impl FileType {
     pub fn highlighting_options(&self) -> &HighlightingOptions {
         &self.hl_opts
     }
+    pub fn expand_tab(&self) -> bool {
+        self.expand_tab
+    }
     pub fn from(file_name: &str) -> Self {
         if file_name.ends_with(".rs") {
             return Self {
@@ -110,6 +118,7 @@ impl FileType {
                         "f64".to_string(),
                     ],
                 },
+                expand_tab: true,
             };
         }
         if file_name.ends_with(".py") {
@@ -182,6 +191,84 @@ impl FileType {
                         "await".to_string(),
                     ],
                 },
+                expand_tab: true,
+            };
+        }
+        if file_name.ends_with(".go") {
+            return Self {
+                name: String::from("Go"),
+                hl_opts: HighlightingOptions {
+                    numbers: true,
+                    strings: true,
+                    characters: true,
+                    comments: true,
+                    multiline_comments: true,
+                    primary_keywords: vec![
+                        "break".to_string(),
+                        "case".to_string(),
+                        "chan".to_string(),
+                        "const".to_string(),
+                        "continue".to_string(),
+                        "default".to_string(),
+                        "defer".to_string(),
+                        "else".to_string(),
+                        "fallthrough".to_string(),
+                        "for".to_string(),
+                        "func".to_string(),
+                        "go".to_string(),
+                        "goto".to_string(),
+                        "if".to_string(),
+                        "import".to_string(),
+                        "interface".to_string(),
+                        "map".to_string(),
+                        "package".to_string(),
+                        "range".to_string(),
+                        "return".to_string(),
+                        "select".to_string(),
+                        "struct".to_string(),
+                        "switch".to_string(),
+                        "type".to_string(),
+                        "var".to_string(),
+                    ],
+                    secondary_keywords: vec![
+                        "bool".to_string(),
+                        "byte".to_string(),
+                        "error".to_string(),
+                        "float32".to_string(),
+                        "float64".to_string(),
+                        "int".to_string(),
+                        "int8".to_string(),
+                        "int16".to_string(),
+                        "int32".to_string(),
+                        "int64".to_string(),
+                        "rune".to_string(),
+                        "string".to_string(),
+                        "uint".to_string(),
+                        "uint8".to_string(),
+                        "uint16".to_string(),
+                        "uint32".to_string(),
+                        "uint64".to_string(),
+                        "uintptr".to_string(),
+                        "true".to_string(),
+                        "false".to_string(),
+                        "nil".to_string(),
+                        "iota".to_string(),
+                    ],
+                },
+                expand_tab: false,
+            };
+        }
+        // Recipes in a Makefile have to start with a tab.
+        let base_name = Path::new(file_name)
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().to_string());
+        if ["Makefile", "makefile", "GNUmakefile"].contains(&base_name.as_str())
+            || file_name.ends_with(".mk")
+        {
+            return Self {
+                name: String::from("Makefile"),
+                hl_opts: HighlightingOptions::default(),
+                expand_tab: false,
             };
         }
         Self::default()
@@ -0,0 +1,92 @@
+use crate::line_ending::split_lines;
+use crate::SearchQuery;
+use crate::TextEncoding;
+use ignore::WalkBuilder;
+use std::fs;
+use std::path::Path;
+
+// Files larger than this are not worth reading whole just to search them.
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+// How far into a file to look for a NUL byte, which marks it as binary.
+const BINARY_CHECK_LEN: usize = 8000;
+const MAX_MATCHES: usize = 10_000;
+
+// A line of a file with a match in it, counted from 0.
+pub struct GrepMatch {
+    pub path: String,
+    pub line: usize,
+    pub text: String,
+}
+
+// Every line under `directory` that `query` matches, file by file in name
+// order. Whatever `.gitignore` files ignore is skipped, as are binary files.
+// The flag is set when there were too many matches to list them all.
+pub fn grep(directory: &Path, query: &SearchQuery) -> (Vec<GrepMatch>, bool) {
+    let mut matches = Vec::new();
+    let walk = WalkBuilder::new(directory)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walk.filter_map(Result::ok) {
+        if !entry.file_type().map_or(false, |file_type| file_type.is_file()) {
+            continue;
+        }
+        let path = entry.path();
+        if entry.metadata().map_or(true, |metadata| metadata.len() > MAX_FILE_SIZE) {
+            continue;
+        }
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        if bytes.iter().take(BINARY_CHECK_LEN).any(|byte| *byte == 0) {
+            continue;
+        }
+        let encoding = TextEncoding::detect(&bytes);
+        let text = encoding.decode(bytes.strip_prefix(encoding.bom()).unwrap_or(&bytes));
+        let name = path.strip_prefix(directory).unwrap_or(path).to_string_lossy();
+        for (line, (value, _)) in split_lines(&text, true).into_iter().enumerate() {
+            if query.find_at(value, 0).is_none() {
+                continue;
+            }
+            if matches.len() >= MAX_MATCHES {
+                return (matches, true);
+            }
+            matches.push(GrepMatch {
+                path: name.to_string(),
+                line,
+                text: value.to_string(),
+            });
+        }
+    }
+    (matches, false)
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::SearchOptions;
+
+    #[test]
+    fn test_grep() {
+        let directory = std::env::temp_dir().join(format!("hecto-grep-{}", std::process::id()));
+        fs::create_dir_all(directory.join("src")).unwrap();
+        fs::create_dir_all(directory.join("target")).unwrap();
+        fs::write(directory.join(".gitignore"), "target/\n").unwrap();
+        fs::write(directory.join("src/main.rs"), "fn main() {\n    println!(\"中文\");\n}\n").unwrap();
+        fs::write(directory.join("notes.txt"), "main\r\nother\r\n").unwrap();
+        fs::write(directory.join("target/out.rs"), "fn main() {}\n").unwrap();
+        fs::write(directory.join("data.bin"), b"main\0").unwrap();
+        let query = SearchQuery::new("main", SearchOptions::default()).unwrap();
+        let (matches, truncated) = grep(&directory, &query);
+        let found: Vec<(&str, usize, &str)> = matches
+            .iter()
+            .map(|found| (found.path.as_str(), found.line, found.text.as_str()))
+            .collect();
+        assert_eq!(found, vec![("notes.txt", 0, "main"), ("src/main.rs", 0, "fn main() {")]);
+        assert!(!truncated);
+        let query = SearchQuery::new("中", SearchOptions::default()).unwrap();
+        assert_eq!(grep(&directory, &query).0[0].line, 1);
+        fs::remove_dir_all(directory).ok();
+    }
+}
@@ -6,6 +6,9 @@ pub enum Type {
     None,
     Number,
     Match,
+    // The match a replace is asking about.
+    CurrentMatch,
+    Capture,
     String,
     Character,
     Comment,
@@ -19,6 +22,8 @@ impl Type {
         match self {
             Type::Number => Color::Rgb { r: 220, g: 163, b: 163 },
             Type::Match => Color::Rgb { r: 38, g: 139, b: 210 },
+            Type::CurrentMatch => Color::Rgb { r: 220, g: 50, b: 47 },
+            Type::Capture => Color::Rgb { r: 203, g: 75, b: 22 },
             Type::String => Color::Rgb { r: 211, g: 54, b: 130 },
             Type::Character => Color::Rgb { r: 108, g: 113, b: 196 },
             Type::Comment | Type::MultilineComment => Color::Rgb { r: 133, g: 153, b: 0 },
@@ -27,4 +32,8 @@ impl Type {
             _ => Color::Rgb { r: 255, g: 255, b: 255 },
         }
     }
+    // Colours that come from a search rather than from the text itself.
+    pub fn is_match(self) -> bool {
+        matches!(self, Type::Match | Type::CurrentMatch | Type::Capture)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,685 @@
+use crate::LineEnding;
+use crate::Position;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Clone)]
+pub enum Operation {
+    Insert { at: Position, text: String },
+    Delete { at: Position, text: String },
+    // Takes `old` out at `at` and puts `new` in its place in one step, for
+    // edits where removing first could join the graphemes on either side.
+    Replace { at: Position, old: String, new: String },
+    // The row that is split, or that the next one is joined onto, ends in
+    // `ending` while the rows are apart; joined, it takes the next row's.
+    Split { at: Position, ending: Option<LineEnding> },
+    Join { at: Position, ending: Option<LineEnding> },
+    InsertRow { y: usize },
+    RemoveRow { y: usize },
+    // Changes the ending new lines get from `from` to `to`, and the ending
+    // of each row listed as `(y, from, to)`.
+    SetEndings {
+        from: LineEnding,
+        to: LineEnding,
+        rows: Vec<(usize, Option<LineEnding>, Option<LineEnding>)>,
+    },
+}
+
+impl Operation {
+    pub fn inverse(&self) -> Self {
+        match self {
+            Self::Insert { at, text } => Self::Delete {
+                at: at.clone(),
+                text: text.clone(),
+            },
+            Self::Delete { at, text } => Self::Insert {
+                at: at.clone(),
+                text: text.clone(),
+            },
+            Self::Replace { at, old, new } => Self::Replace {
+                at: at.clone(),
+                old: new.clone(),
+                new: old.clone(),
+            },
+            Self::Split { at, ending } => Self::Join {
+                at: at.clone(),
+                ending: *ending,
+            },
+            Self::Join { at, ending } => Self::Split {
+                at: at.clone(),
+                ending: *ending,
+            },
+            Self::InsertRow { y } => Self::RemoveRow { y: *y },
+            Self::RemoveRow { y } => Self::InsertRow { y: *y },
+            Self::SetEndings { from, to, rows } => Self::SetEndings {
+                from: *to,
+                to: *from,
+                rows: rows.iter().map(|(y, from, to)| (*y, *to, *from)).collect(),
+            },
+        }
+    }
+    pub fn position(&self) -> Position {
+        match self {
+            Self::Insert { at, .. }
+            | Self::Delete { at, .. }
+            | Self::Replace { at, .. }
+            | Self::Split { at, .. }
+            | Self::Join { at, .. } => at.clone(),
+            Self::InsertRow { y } | Self::RemoveRow { y } => Position { x: 0, y: *y },
+            Self::SetEndings { rows, .. } => Position {
+                x: 0,
+                y: rows.first().map_or(0, |(y, _, _)| *y),
+            },
+        }
+    }
+    fn text_len(&self) -> usize {
+        match self {
+            Self::Insert { text, .. } | Self::Delete { text, .. } => text.graphemes(true).count(),
+            _ => 0,
+        }
+    }
+    // Folds `next` into `self` when both touch the same run of text, so typing
+    // or backspacing a word is stored as one operation instead of one per key.
+    // `len` is the grapheme count of `self`'s text, kept by the caller so long
+    // runs are not re-counted on every keystroke.
+    #[allow(clippy::integer_arithmetic)]
+    fn merge(&mut self, len: usize, next: &Self) -> bool {
+        match (self, next) {
+            (
+                Self::Insert { at, text },
+                Self::Insert {
+                    at: next_at,
+                    text: next_text,
+                },
+            ) if next_at.y == at.y && next_at.x == at.x + len && !joins(text, next_text) => {
+                text.push_str(next_text);
+                true
+            }
+            (
+                Self::Delete { at, text },
+                Self::Delete {
+                    at: next_at,
+                    text: next_text,
+                },
+            ) if next_at.y == at.y => {
+                if next_at.x == at.x && !joins(text, next_text) {
+                    text.push_str(next_text);
+                    true
+                } else if next_at.x + next.text_len() == at.x && !joins(next_text, text) {
+                    text.insert_str(0, next_text);
+                    at.x = next_at.x;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+// Whether the last grapheme of `first` and the first of `second` would make one
+// grapheme if the texts were put together, which would leave a merged text
+// with fewer graphemes than its parts.
+fn joins(first: &str, second: &str) -> bool {
+    match (first.graphemes(true).next_back(), second.graphemes(true).next()) {
+        (Some(last), Some(next)) => format!("{}{}", last, next).graphemes(true).nth(1).is_none(),
+        _ => false,
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Change {
+    operations: Vec<Operation>,
+    cursor_before: Option<Position>,
+    cursor_after: Option<Position>,
+    last_len: usize,
+}
+
+impl Change {
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+    pub fn position(&self) -> Position {
+        self.operations
+            .first()
+            .map(Operation::position)
+            .unwrap_or_default()
+    }
+    pub fn cursor_before(&self) -> Position {
+        self.cursor_before
+            .clone()
+            .unwrap_or_else(|| self.position())
+    }
+    pub fn cursor_after(&self) -> Position {
+        self.cursor_after
+            .clone()
+            .unwrap_or_else(|| self.position())
+    }
+    pub fn first_row(&self) -> usize {
+        self.operations
+            .iter()
+            .map(|operation| operation.position().y)
+            .min()
+            .unwrap_or(0)
+    }
+    fn push(&mut self, operation: Operation) {
+        let len = operation.text_len();
+        if let Some(last) = self.operations.last_mut() {
+            if last.merge(self.last_len, &operation) {
+                self.last_len = self.last_len.saturating_add(len);
+                return;
+            }
+        }
+        self.operations.push(operation);
+        self.last_len = len;
+    }
+}
+
+struct Node {
+    change: Change,
+    parent: usize,
+    redo_child: Option<usize>,
+    time: SystemTime,
+}
+
+impl Node {
+    fn new(parent: usize) -> Self {
+        Self {
+            change: Change::default(),
+            parent,
+            redo_child: None,
+            time: SystemTime::now(),
+        }
+    }
+}
+
+pub enum Step {
+    Undo(Change),
+    Redo(Change),
+}
+
+pub struct Branch {
+    pub state: usize,
+    pub time: SystemTime,
+}
+
+// Every batch of edits is a node whose parent is the state it was made in, so
+// typing after an undo starts a new branch instead of discarding the old one.
+// Node ids grow with creation time and double as chronological state numbers;
+// node 0 is the state the history started from.
+pub struct History {
+    nodes: Vec<Node>,
+    current: usize,
+    last_edit_time: Option<Instant>,
+    batch_duration: Duration,
+    // Set between `begin_group` and `end_group`, while every edit goes into
+    // the same change however long it takes.
+    grouping: bool,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::new(0)],
+            current: 0,
+            last_edit_time: None,
+            batch_duration: Duration::from_millis(1000),
+            grouping: false,
+        }
+    }
+}
+
+impl History {
+    fn should_create_new_state(&mut self) -> bool {
+        if self.grouping && self.last_edit_time.is_some() {
+            return false;
+        }
+        if let Some(last_time) = self.last_edit_time {
+            if last_time.elapsed() > self.batch_duration {
+                self.last_edit_time = Some(Instant::now());
+                true
+            } else {
+                false
+            }
+        } else {
+            self.last_edit_time = Some(Instant::now());
+            true
+        }
+    }
+    pub fn record(&mut self, operation: Operation) {
+        if self.should_create_new_state() {
+            let id = self.nodes.len();
+            self.nodes.push(Node::new(self.current));
+            if let Some(node) = self.nodes.get_mut(self.current) {
+                node.redo_child = Some(id);
+            }
+            self.current = id;
+        }
+        if let Some(node) = self.nodes.get_mut(self.current) {
+            node.change.push(operation);
+        }
+    }
+    // Makes the following edits one change, undone in a single step, for
+    // commands like replace that are not finished within a batch.
+    pub fn begin_group(&mut self) {
+        self.last_edit_time = None;
+        self.grouping = true;
+    }
+    pub fn end_group(&mut self) {
+        self.grouping = false;
+        self.last_edit_time = None;
+    }
+    // Attaches the editor cursor to the change that is still being batched;
+    // the first call of a batch fixes where the cursor was before it started.
+    pub fn set_cursors(&mut self, before: &Position, after: &Position) {
+        if self.last_edit_time.is_none() || self.current == 0 {
+            return;
+        }
+        if let Some(node) = self.nodes.get_mut(self.current) {
+            if node.change.cursor_before.is_none() {
+                node.change.cursor_before = Some(before.clone());
+            }
+            node.change.cursor_after = Some(after.clone());
+        }
+    }
+    pub fn undo(&mut self) -> Option<&Change> {
+        if self.current == 0 {
+            return None;
+        }
+        let id = self.current;
+        let parent = self.nodes.get(id)?.parent;
+        if let Some(node) = self.nodes.get_mut(parent) {
+            node.redo_child = Some(id);
+        }
+        self.current = parent;
+        self.last_edit_time = None;
+        self.nodes.get(id).map(|node| &node.change)
+    }
+    pub fn redo(&mut self) -> Option<&Change> {
+        let child = self.nodes.get(self.current)?.redo_child?;
+        self.current = child;
+        self.last_edit_time = None;
+        self.nodes.get(child).map(|node| &node.change)
+    }
+    pub fn state(&self) -> usize {
+        self.current
+    }
+    // Moves to any state in the tree by undoing up to the closest common
+    // ancestor and redoing down the target's branch.
+    pub fn travel(&mut self, target: usize) -> Vec<Step> {
+        let mut steps = Vec::new();
+        if target >= self.nodes.len() {
+            return steps;
+        }
+        let mut path = Vec::new();
+        let mut id = target;
+        while id != 0 {
+            path.push(id);
+            id = self.nodes.get(id).map_or(0, |node| node.parent);
+        }
+        while self.current != 0 && !path.contains(&self.current) {
+            if let Some(change) = self.undo() {
+                steps.push(Step::Undo(change.clone()));
+            }
+        }
+        path.reverse();
+        let start = path
+            .iter()
+            .position(|id| *id == self.current)
+            .map_or(0, |index| index.saturating_add(1));
+        for child in path.into_iter().skip(start) {
+            if let Some(node) = self.nodes.get_mut(self.current) {
+                node.redo_child = Some(child);
+            }
+            if let Some(change) = self.redo() {
+                steps.push(Step::Redo(change.clone()));
+            }
+        }
+        steps
+    }
+    pub fn branches(&self) -> Vec<Branch> {
+        let mut has_children = vec![false; self.nodes.len()];
+        for node in self.nodes.iter().skip(1) {
+            if let Some(has_child) = has_children.get_mut(node.parent) {
+                *has_child = true;
+            }
+        }
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| !has_children.get(*id).copied().unwrap_or(false))
+            .map(|(state, node)| Branch {
+                state,
+                time: node.time,
+            })
+            .collect()
+    }
+    pub fn serialize(&self) -> String {
+        let mut result = format!("{}\ncurrent {}\n", UNDO_FILE_HEADER, self.current);
+        for node in self.nodes.iter().skip(1) {
+            result.push_str(&format!(
+                "node {} {} {} {} {}\n",
+                node.parent,
+                node.redo_child
+                    .map_or_else(|| "-".to_string(), |child| child.to_string()),
+                node.time
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_secs()),
+                format_position(node.change.cursor_before.as_ref()),
+                format_position(node.change.cursor_after.as_ref())
+            ));
+            for operation in &node.change.operations {
+                result.push_str(&format_operation(operation));
+                result.push('\n');
+            }
+        }
+        result
+    }
+    pub fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        if lines.next()? != UNDO_FILE_HEADER {
+            return None;
+        }
+        let current = lines.next()?.strip_prefix("current ")?.parse().ok()?;
+        let mut nodes = vec![Node::new(0)];
+        for line in lines {
+            if let Some(fields) = line.strip_prefix("node ") {
+                let fields: Vec<&str> = fields.split(' ').collect();
+                if let [parent, redo_child, time, before, after] = fields[..] {
+                    let parent = parent.parse().ok()?;
+                    if parent >= nodes.len() {
+                        return None;
+                    }
+                    nodes.push(Node {
+                        change: Change {
+                            operations: Vec::new(),
+                            cursor_before: parse_position(before)?,
+                            cursor_after: parse_position(after)?,
+                            ..Change::default()
+                        },
+                        parent,
+                        redo_child: if redo_child == "-" {
+                            None
+                        } else {
+                            Some(redo_child.parse().ok()?)
+                        },
+                        time: UNIX_EPOCH.checked_add(Duration::from_secs(time.parse().ok()?))?,
+                    });
+                } else {
+                    return None;
+                }
+            } else {
+                nodes.last_mut()?.change.operations.push(parse_operation(line)?);
+            }
+        }
+        if current >= nodes.len()
+            || nodes
+                .iter()
+                .any(|node| node.redo_child.map_or(false, |child| child >= nodes.len()))
+        {
+            return None;
+        }
+        Some(Self {
+            nodes,
+            current,
+            ..Self::default()
+        })
+    }
+}
+
+const UNDO_FILE_HEADER: &str = "hecto-undo 3";
+
+fn format_position(position: Option<&Position>) -> String {
+    if let Some(position) = position {
+        format!("{},{}", position.y, position.x)
+    } else {
+        "-".to_string()
+    }
+}
+
+fn parse_position(value: &str) -> Option<Option<Position>> {
+    if value == "-" {
+        return Some(None);
+    }
+    let (y, x) = value.split_once(',')?;
+    Some(Some(Position {
+        x: x.parse().ok()?,
+        y: y.parse().ok()?,
+    }))
+}
+
+fn format_operation(operation: &Operation) -> String {
+    match operation {
+        Operation::Insert { at, text } => format!("insert {} {} {}", at.y, at.x, escape(text)),
+        Operation::Delete { at, text } => format!("delete {} {} {}", at.y, at.x, escape(text)),
+        Operation::Replace { at, old, new } => {
+            // Spaces in `old` are escaped, so the first space ends it.
+            format!("replace {} {} {} {}", at.y, at.x, escape(old).replace(' ', "\\s"), escape(new))
+        }
+        Operation::Split { at, ending } => {
+            format!("split {} {} {}", at.y, at.x, format_ending(*ending))
+        }
+        Operation::Join { at, ending } => {
+            format!("join {} {} {}", at.y, at.x, format_ending(*ending))
+        }
+        Operation::InsertRow { y } => format!("insert-row {}", y),
+        Operation::RemoveRow { y } => format!("remove-row {}", y),
+        Operation::SetEndings { from, to, rows } => {
+            let rows: Vec<String> = rows
+                .iter()
+                .map(|(y, from, to)| format!("{},{},{}", y, format_ending(*from), format_ending(*to)))
+                .collect();
+            format!("endings {} {} {}", from.name(), to.name(), rows.join(" "))
+                .trim_end()
+                .to_string()
+        }
+    }
+}
+
+fn format_ending(ending: Option<LineEnding>) -> &'static str {
+    ending.map_or("-", LineEnding::name)
+}
+
+fn parse_ending(value: &str) -> Option<Option<LineEnding>> {
+    if value == "-" {
+        return Some(None);
+    }
+    LineEnding::from_name(value).map(Some)
+}
+
+fn parse_endings(fields: &str) -> Option<Operation> {
+    let mut fields = fields.split(' ');
+    let from = LineEnding::from_name(fields.next()?)?;
+    let to = LineEnding::from_name(fields.next()?)?;
+    let mut rows = Vec::new();
+    for row in fields {
+        if let [y, from, to] = row.split(',').collect::<Vec<&str>>()[..] {
+            rows.push((y.parse().ok()?, parse_ending(from)?, parse_ending(to)?));
+        } else {
+            return None;
+        }
+    }
+    Some(Operation::SetEndings { from, to, rows })
+}
+
+fn parse_operation(line: &str) -> Option<Operation> {
+    if let Some(fields) = line.strip_prefix("endings ") {
+        return parse_endings(fields);
+    }
+    let mut fields = line.splitn(4, ' ');
+    let kind = fields.next()?;
+    let y = fields.next()?.parse().ok()?;
+    if kind == "insert-row" {
+        return Some(Operation::InsertRow { y });
+    }
+    if kind == "remove-row" {
+        return Some(Operation::RemoveRow { y });
+    }
+    let at = Position {
+        x: fields.next()?.parse().ok()?,
+        y,
+    };
+    match kind {
+        "insert" => Some(Operation::Insert {
+            at,
+            text: unescape(fields.next()?),
+        }),
+        "delete" => Some(Operation::Delete {
+            at,
+            text: unescape(fields.next()?),
+        }),
+        "replace" => {
+            let (old, new) = fields.next()?.split_once(' ')?;
+            Some(Operation::Replace {
+                at,
+                old: unescape(old),
+                new: unescape(new),
+            })
+        }
+        "split" => Some(Operation::Split {
+            at,
+            ending: parse_ending(fields.next()?)?,
+        }),
+        "join" => Some(Operation::Join {
+            at,
+            ending: parse_ending(fields.next()?)?,
+        }),
+        _ => None,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some('s') => result.push(' '),
+                Some(other) => result.push(other),
+                None => result.push(c),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_merge_typing_and_backspace() {
+        let mut change = Change::default();
+        change.push(Operation::Insert {
+            at: Position { x: 0, y: 0 },
+            text: "a".to_string(),
+        });
+        change.push(Operation::Insert {
+            at: Position { x: 1, y: 0 },
+            text: "b".to_string(),
+        });
+        change.push(Operation::Delete {
+            at: Position { x: 4, y: 0 },
+            text: "d".to_string(),
+        });
+        change.push(Operation::Delete {
+            at: Position { x: 3, y: 0 },
+            text: "c".to_string(),
+        });
+        assert_eq!(change.operations().len(), 2);
+        if let Some(Operation::Insert { text, .. }) = change.operations().first() {
+            assert_eq!(text, "ab");
+        } else {
+            panic!("expected an insert");
+        }
+        if let Some(Operation::Delete { at, text }) = change.operations().last() {
+            assert_eq!((at.x, text.as_str()), (3, "cd"));
+        } else {
+            panic!("expected a delete");
+        }
+    }
+
+    #[test]
+    fn test_group() {
+        let mut history = History::default();
+        history.record(Operation::InsertRow { y: 0 });
+        history.begin_group();
+        history.batch_duration = Duration::from_millis(0);
+        history.record(Operation::InsertRow { y: 1 });
+        std::thread::sleep(Duration::from_millis(2));
+        history.record(Operation::InsertRow { y: 2 });
+        history.end_group();
+        history.record(Operation::InsertRow { y: 3 });
+        assert_eq!(history.state(), 3);
+        assert!(history.undo().is_some());
+        assert_eq!(history.undo().map(|change| change.operations().len()), Some(2));
+        assert_eq!(history.state(), 1);
+    }
+
+    #[test]
+    fn test_serialize_roundtrip() {
+        let mut history = History::default();
+        history.record(Operation::Insert {
+            at: Position { x: 2, y: 1 },
+            text: "a\\b\nc".to_string(),
+        });
+        history.record(Operation::Split {
+            at: Position { x: 3, y: 1 },
+            ending: Some(LineEnding::CrLf),
+        });
+        history.record(Operation::Replace {
+            at: Position { x: 1, y: 0 },
+            old: "a \\s".to_string(),
+            new: "b c".to_string(),
+        });
+        history.record(Operation::SetEndings {
+            from: LineEnding::CrLf,
+            to: LineEnding::Lf,
+            rows: vec![(0, Some(LineEnding::CrLf), None), (1, None, Some(LineEnding::Cr))],
+        });
+        history.set_cursors(&Position { x: 2, y: 1 }, &Position { x: 0, y: 2 });
+        let parsed = History::parse(&history.serialize());
+        assert!(parsed.is_some());
+        if let Some(parsed) = parsed {
+            assert_eq!(parsed.serialize(), history.serialize());
+            assert_eq!(parsed.current, 1);
+            let replaced = parsed.nodes.get(1).and_then(|node| node.change.operations().get(2));
+            assert!(matches!(
+                replaced,
+                Some(Operation::Replace { old, new, .. }) if old == "a \\s" && new == "b c"
+            ));
+        }
+        assert!(History::parse("hecto-undo 3\ncurrent 2\n").is_none());
+    }
+
+    #[test]
+    fn test_travel_between_branches() {
+        let mut history = History::default();
+        history.record(Operation::InsertRow { y: 0 });
+        assert!(history.undo().is_some());
+        history.record(Operation::InsertRow { y: 1 });
+        assert_eq!(history.branches().len(), 2);
+        assert!(history.undo().is_some());
+        assert!(history.redo().is_some());
+        assert_eq!(history.state(), 2);
+
+        let steps = history.travel(1);
+        assert_eq!(history.state(), 1);
+        assert!(matches!(
+            steps.as_slice(),
+            [Step::Undo(undone), Step::Redo(redone)]
+                if undone.position().y == 1 && redone.position().y == 0
+        ));
+    }
+}
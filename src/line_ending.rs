@@ -0,0 +1,131 @@
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl Default for LineEnding {
+    fn default() -> Self {
+        if cfg!(windows) {
+            Self::CrLf
+        } else {
+            Self::Lf
+        }
+    }
+}
+
+impl LineEnding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lf => "LF",
+            Self::CrLf => "CRLF",
+            Self::Cr => "CR",
+        }
+    }
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Lf => "\n",
+            Self::CrLf => "\r\n",
+            Self::Cr => "\r",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "lf" | "unix" => Some(Self::Lf),
+            "crlf" | "dos" | "windows" => Some(Self::CrLf),
+            "cr" | "mac" => Some(Self::Cr),
+            _ => None,
+        }
+    }
+}
+
+// Splits text into lines and the ending that terminated each of them; the
+// last line has no ending when the text does not end with one. A lone `\r`
+// only ends a line when `split_cr` is set.
+pub fn split_lines(text: &str, split_cr: bool) -> Vec<(&str, Option<LineEnding>)> {
+    let mut lines = Vec::new();
+    let bytes = text.as_bytes();
+    let mut start = 0;
+    let mut index = 0;
+    while let Some(byte) = bytes.get(index) {
+        let ending = match byte {
+            b'\n' => Some((LineEnding::Lf, 1)),
+            b'\r' if bytes.get(index.saturating_add(1)) == Some(&b'\n') => {
+                Some((LineEnding::CrLf, 2))
+            }
+            b'\r' if split_cr => Some((LineEnding::Cr, 1)),
+            _ => None,
+        };
+        if let Some((ending, len)) = ending {
+            lines.push((text.get(start..index).unwrap_or_default(), Some(ending)));
+            index = index.saturating_add(len);
+            start = index;
+        } else {
+            index = index.saturating_add(1);
+        }
+    }
+    if start < bytes.len() {
+        lines.push((text.get(start..).unwrap_or_default(), None));
+    }
+    lines
+}
+
+// Returns the most common line ending and whether the lines used more than
+// one kind.
+pub fn detect<I: IntoIterator<Item = LineEnding>>(endings: I) -> (LineEnding, bool) {
+    let mut counts = [0_usize; 3];
+    for ending in endings {
+        let index = match ending {
+            LineEnding::Lf => 0,
+            LineEnding::CrLf => 1,
+            LineEnding::Cr => 2,
+        };
+        if let Some(count) = counts.get_mut(index) {
+            *count = count.saturating_add(1);
+        }
+    }
+    let mixed = counts.iter().filter(|count| **count > 0).count() > 1;
+    let [lf, crlf, cr] = counts;
+    let ending = if lf == 0 && crlf == 0 && cr == 0 {
+        LineEnding::default()
+    } else if lf >= crlf && lf >= cr {
+        LineEnding::Lf
+    } else if crlf >= cr {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Cr
+    };
+    (ending, mixed)
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_split_lines() {
+        assert_eq!(
+            split_lines("a\r\nb\rc\nd", true),
+            vec![
+                ("a", Some(LineEnding::CrLf)),
+                ("b", Some(LineEnding::Cr)),
+                ("c", Some(LineEnding::Lf)),
+                ("d", None)
+            ]
+        );
+        assert_eq!(
+            split_lines("a\rb\n\n", false),
+            vec![("a\rb", Some(LineEnding::Lf)), ("", Some(LineEnding::Lf))]
+        );
+        assert!(split_lines("", true).is_empty());
+    }
+
+    #[test]
+    fn test_detect() {
+        let endings = |text| split_lines(text, true).into_iter().filter_map(|(_, ending)| ending);
+        assert_eq!(detect(endings("a\r\nb\r\nc")), (LineEnding::CrLf, false));
+        assert_eq!(detect(endings("a\nb\r\nc\n")), (LineEnding::Lf, true));
+        assert_eq!(detect(endings("a\rb\r")), (LineEnding::Cr, false));
+    }
+}
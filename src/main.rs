@@ -7,23 +7,204 @@
     clippy::wildcard_enum_match_arm,
     clippy::else_if_without_else
 )]
+mod atomic;
+mod completion;
 mod document;
 mod editor;
+mod encoding;
 mod filetype;
+mod grep;
 mod highlighting;
+mod history;
+mod line_ending;
+mod prompt;
+mod rope;
 mod row;
+mod search;
+mod stream;
 mod terminal;
+mod width;
 pub use document::Document;
 use editor::Editor;
 pub use editor::Position;
 pub use editor::SearchDirection;
 pub use filetype::FileType;
 pub use filetype::HighlightingOptions;
+pub use encoding::TextEncoding;
+pub use line_ending::LineEnding;
 pub use row::Row;
+pub use search::SearchOptions;
+pub use search::SearchQuery;
 pub use terminal::Terminal;
+use std::env;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "Usage: hecto [OPTIONS] [+LINE] [FILE[:LINE[:COLUMN]]]...
+
+A FILE of - reads standard input. When standard output is not a terminal, the
+current buffer is written to it on quit.
+
+Options:
+  --readonly           Open the files without allowing changes
+  --encoding ENCODING  Decode the files as ENCODING instead of detecting it
+  --ambiguous-wide     Show East Asian ambiguous-width characters in two
+                       columns, for terminals that do
+  --tab-width N        Put tab stops every N columns (default 4)
+  -h, --help           Print this help and exit
+  -V, --version        Print the version and exit";
+
+// A file named on the command line and where to put the cursor in it; lines
+// and columns count from 1.
+pub struct FileArg {
+    pub name: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Args {
+    pub files: Vec<FileArg>,
+    pub readonly: bool,
+    pub encoding: Option<TextEncoding>,
+    pub ambiguous_wide: bool,
+    pub tab_width: Option<usize>,
+}
+
+enum Command {
+    Edit(Args),
+    Help,
+    Version,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut arguments: I) -> Result<Command, String> {
+    let mut args = Args::default();
+    let mut line = None;
+    let mut only_files = false;
+    while let Some(argument) = arguments.next() {
+        if !only_files && argument.starts_with('-') && argument.len() > 1 {
+            match argument.as_str() {
+                "--" => only_files = true,
+                "-h" | "--help" => return Ok(Command::Help),
+                "-V" | "--version" => return Ok(Command::Version),
+                "--readonly" | "-R" => args.readonly = true,
+                "--ambiguous-wide" => args.ambiguous_wide = true,
+                "--tab-width" => {
+                    let width = arguments
+                        .next()
+                        .ok_or_else(|| "--tab-width needs a number".to_string())?;
+                    args.tab_width = match width.parse() {
+                        Ok(width) if width > 0 => Some(width),
+                        _ => return Err(format!("invalid tab width {}", width)),
+                    };
+                }
+                "--encoding" => {
+                    let label = arguments
+                        .next()
+                        .ok_or_else(|| "--encoding needs an encoding name".to_string())?;
+                    args.encoding = Some(
+                        TextEncoding::from_label(&label)
+                            .ok_or_else(|| format!("unknown encoding {}", label))?,
+                    );
+                }
+                _ => return Err(format!("unknown option {}", argument)),
+            }
+        } else if let (false, Some(number)) = (only_files, argument.strip_prefix('+')) {
+            line = Some(
+                number
+                    .parse()
+                    .map_err(|_| format!("invalid line number {}", argument))?,
+            );
+        } else {
+            let mut file = parse_file(&argument);
+            if line.is_some() {
+                file.line = line.take();
+            }
+            args.files.push(file);
+        }
+    }
+    if let Some(line) = line {
+        return Err(format!("no file after +{}", line));
+    }
+    Ok(Command::Edit(args))
+}
+
+// Splits `name:line:column` and `name:line`, unless a file with the whole
+// name exists.
+fn parse_file(argument: &str) -> FileArg {
+    let mut name = argument;
+    let mut numbers = Vec::new();
+    while numbers.len() < 2 && !Path::new(name).exists() {
+        match name.rsplit_once(':') {
+            Some((rest, number)) if !rest.is_empty() => match number.parse::<usize>() {
+                Ok(number) => {
+                    numbers.insert(0, number);
+                    name = rest;
+                }
+                Err(_) => break,
+            },
+            _ => break,
+        }
+    }
+    FileArg {
+        name: name.to_string(),
+        line: numbers.first().copied(),
+        column: numbers.get(1).copied(),
+    }
+}
 
 fn main() {
-    let position = Position { x: 0, y: 0 };
-    let mut editor = Editor::default();
-    editor.run()
+    match parse_args(env::args().skip(1)) {
+        Ok(Command::Edit(args)) => {
+            let mut editor = Editor::new(args);
+            editor.run()
+        }
+        Ok(Command::Help) => println!("{}", USAGE),
+        Ok(Command::Version) => println!("hecto {}", env!("CARGO_PKG_VERSION")),
+        Err(error) => {
+            eprintln!("hecto: {}\n\n{}", error, USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> Args {
+        match parse_args(arguments.iter().map(ToString::to_string)) {
+            Ok(Command::Edit(args)) => args,
+            _ => panic!("not an edit command"),
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&["--readonly", "--ambiguous-wide", "a.rs:3:7", "+12", "b.rs", "c.rs:9", "-", "--", "-d"]);
+        assert!(args.readonly);
+        assert!(args.ambiguous_wide);
+        let files: Vec<(&str, Option<usize>, Option<usize>)> = args
+            .files
+            .iter()
+            .map(|file| (file.name.as_str(), file.line, file.column))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("a.rs", Some(3), Some(7)),
+                ("b.rs", Some(12), None),
+                ("c.rs", Some(9), None),
+                ("-", None, None),
+                ("-d", None, None)
+            ]
+        );
+        assert_eq!(parse_file("notes:txt").name, "notes:txt");
+        assert!(parse(&["--encoding", "gbk"]).encoding.is_some());
+        assert_eq!(parse(&["--tab-width", "8"]).tab_width, Some(8));
+        assert!(parse_args(["--tab-width".to_string(), "0".to_string()].into_iter()).is_err());
+        assert!(parse_args(["--encoding".to_string(), "nope".to_string()].into_iter()).is_err());
+        assert!(parse_args(["a.rs".to_string(), "+3".to_string()].into_iter()).is_err());
+        assert!(matches!(parse_args(["-h".to_string()].into_iter()), Ok(Command::Help)));
+    }
 }
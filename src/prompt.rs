@@ -0,0 +1,194 @@
+use crate::width;
+use unicode_segmentation::UnicodeSegmentation;
+
+const HISTORY_LEN: usize = 100;
+
+// The text typed into a prompt and the cursor in it, which always sits on a
+// grapheme boundary so that editing never splits a character.
+#[derive(Default)]
+pub struct LineInput {
+    text: String,
+    cursor: usize,
+}
+
+impl LineInput {
+    pub fn from(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            cursor: text.len(),
+        }
+    }
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    // Replaces the text, with the cursor at its end.
+    pub fn set(&mut self, text: &str) {
+        self.text = text.to_string();
+        self.cursor = self.text.len();
+    }
+    // How many columns the text before the cursor takes on screen.
+    pub fn cursor_width(&self) -> usize {
+        width::text_width(self.before())
+    }
+    pub fn insert(&mut self, text: &str) {
+        self.text.insert_str(self.cursor, text);
+        self.cursor = self.cursor.saturating_add(text.len());
+    }
+    pub fn left(&mut self) {
+        self.cursor = self.previous_boundary();
+    }
+    pub fn right(&mut self) {
+        self.cursor = self.next_boundary();
+    }
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+    pub fn word_left(&mut self) {
+        self.cursor = self.previous_word();
+    }
+    pub fn word_right(&mut self) {
+        self.cursor = self.next_word();
+    }
+    pub fn backspace(&mut self) {
+        let start = self.previous_boundary();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+    pub fn delete(&mut self) {
+        let end = self.next_boundary();
+        self.text.replace_range(self.cursor..end, "");
+    }
+    pub fn delete_word(&mut self) {
+        let start = self.previous_word();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+    pub fn delete_to_start(&mut self) {
+        self.text.replace_range(..self.cursor, "");
+        self.cursor = 0;
+    }
+    pub fn delete_to_end(&mut self) {
+        self.text.truncate(self.cursor);
+    }
+    // The text before and after the cursor.
+    fn before(&self) -> &str {
+        self.text.get(..self.cursor).unwrap_or_default()
+    }
+    fn after(&self) -> &str {
+        self.text.get(self.cursor..).unwrap_or_default()
+    }
+    fn previous_boundary(&self) -> usize {
+        self.before()
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(index, _)| index)
+    }
+    fn next_boundary(&self) -> usize {
+        self.after()
+            .graphemes(true)
+            .next()
+            .map_or(self.cursor, |grapheme| self.cursor.saturating_add(grapheme.len()))
+    }
+    // The start of the word before the cursor, skipping the separators
+    // between them.
+    fn previous_word(&self) -> usize {
+        let mut graphemes = self.before().grapheme_indices(true).rev().peekable();
+        let mut start = self.cursor;
+        for word in [false, true] {
+            while let Some((index, _)) =
+                graphemes.next_if(|(_, grapheme)| is_word(grapheme) == word)
+            {
+                start = index;
+            }
+        }
+        start
+    }
+    fn next_word(&self) -> usize {
+        let mut graphemes = self.after().grapheme_indices(true).peekable();
+        let mut end = self.cursor;
+        for word in [false, true] {
+            while let Some((index, grapheme)) =
+                graphemes.next_if(|(_, grapheme)| is_word(grapheme) == word)
+            {
+                end = self.cursor.saturating_add(index).saturating_add(grapheme.len());
+            }
+        }
+        end
+    }
+}
+
+fn is_word(grapheme: &str) -> bool {
+    grapheme
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_alphanumeric() || c == '_')
+}
+
+// Past answers to one kind of prompt, oldest first. Giving the same answer
+// again moves it to the end instead of repeating it.
+#[derive(Default)]
+pub struct PromptHistory {
+    entries: Vec<String>,
+}
+
+impl PromptHistory {
+    pub fn push(&mut self, entry: &str) {
+        self.entries.retain(|existing| existing != entry);
+        self.entries.push(entry.to_string());
+        if self.entries.len() > HISTORY_LEN {
+            self.entries.remove(0);
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_line_input() {
+        let mut input = LineInput::from("中文 e\u{301}dit");
+        input.word_left();
+        input.backspace();
+        assert_eq!(input.text(), "中文e\u{301}dit");
+        input.right();
+        assert_eq!(input.cursor_width(), 5);
+        input.backspace();
+        assert_eq!(input.text(), "中文dit");
+        input.insert("粘贴 ");
+        input.home();
+        input.delete();
+        assert_eq!(input.text(), "文粘贴 dit");
+        input.end();
+        input.delete_word();
+        assert_eq!(input.text(), "文粘贴 ");
+        input.word_left();
+        input.delete_to_end();
+        assert_eq!(input.text(), "");
+        input.set("one two");
+        input.home();
+        input.word_right();
+        input.delete_to_start();
+        assert_eq!(input.text(), " two");
+    }
+
+    #[test]
+    fn test_prompt_history() {
+        let mut history = PromptHistory::default();
+        history.push("a");
+        history.push("b");
+        history.push("a");
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0), Some("b"));
+        assert_eq!(history.get(1), Some("a"));
+    }
+}
@@ -0,0 +1,579 @@
+use crate::atomic::write_atomically;
+use crate::document::{extend_hash, EMPTY_HASH};
+use crate::encoding::TextEncoding;
+use crate::line_ending::split_lines;
+use crate::LineEnding;
+use crate::Row;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+const CHUNK_SIZE: usize = 512;
+const READ_BUFFER_SIZE: usize = 1 << 20;
+// Chunks this far from the viewport are dropped again if they were not edited.
+const LOADED_MARGIN: usize = 4;
+
+// A run of whole lines in the file a mapped rope was opened from.
+#[derive(Clone, Copy)]
+struct Span {
+    offset: u64,
+    len: u64,
+    lines: usize,
+    // Only false for the last line of a file without a final newline.
+    terminated: bool,
+}
+
+enum Chunk {
+    // `span` is where the rows came from while they are unedited, so the
+    // chunk can be dropped again and re-read later.
+    Loaded { rows: Vec<Row>, span: Option<Span> },
+    Mapped(Span),
+}
+
+impl Chunk {
+    fn len(&self) -> usize {
+        match self {
+            Self::Loaded { rows, .. } => rows.len(),
+            Self::Mapped(span) => span.lines,
+        }
+    }
+    fn rows(&self) -> Option<&Vec<Row>> {
+        match self {
+            Self::Loaded { rows, .. } => Some(rows),
+            Self::Mapped(_) => None,
+        }
+    }
+    fn rows_mut(&mut self) -> Option<&mut Vec<Row>> {
+        match self {
+            Self::Loaded { rows, .. } => Some(rows),
+            Self::Mapped(_) => None,
+        }
+    }
+}
+
+// Rows are kept in chunks of at most twice `CHUNK_SIZE` with the index of
+// each chunk's first row cached, so inserting or removing a line only shifts
+// one chunk and the start offsets after it instead of the whole document.
+//
+// A rope can also be mapped onto a file: a background thread indexes where
+// each chunk's lines start, and chunks are only read into rows when they are
+// looked at, which lets files larger than memory be viewed.
+#[derive(Default)]
+pub struct Rope {
+    chunks: Vec<Chunk>,
+    starts: Vec<usize>,
+    len: usize,
+    source: Option<File>,
+    index: Option<Receiver<Span>>,
+    encoding: TextEncoding,
+}
+
+impl From<Vec<Row>> for Rope {
+    fn from(rows: Vec<Row>) -> Self {
+        let mut rope = Self::default();
+        let mut rows = rows.into_iter().peekable();
+        while rows.peek().is_some() {
+            rope.starts.push(rope.len);
+            let chunk: Vec<Row> = rows.by_ref().take(CHUNK_SIZE).collect();
+            rope.len = rope.len.saturating_add(chunk.len());
+            rope.chunks.push(Chunk::Loaded {
+                rows: chunk,
+                span: None,
+            });
+        }
+        rope
+    }
+}
+
+impl Rope {
+    // The encoding must be ASCII compatible so that lines can be found by
+    // their `\n` bytes.
+    pub fn map(file_name: &str, encoding: TextEncoding) -> Result<Self, io::Error> {
+        let source = File::open(file_name)?;
+        let mut reader = source.try_clone()?;
+        let mut offset = encoding.bom().len() as u64;
+        reader.seek(SeekFrom::Start(offset))?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = vec![0; READ_BUFFER_SIZE];
+            let mut span = Span {
+                offset,
+                len: 0,
+                lines: 0,
+                terminated: true,
+            };
+            let mut last_byte = b'\n';
+            while let Ok(read) = reader.read(&mut buffer) {
+                if read == 0 {
+                    break;
+                }
+                for (index, byte) in buffer.iter().take(read).enumerate() {
+                    if *byte == b'\n' {
+                        span.lines = span.lines.saturating_add(1);
+                        if span.lines == CHUNK_SIZE {
+                            let end = offset.saturating_add(index as u64).saturating_add(1);
+                            span.len = end.saturating_sub(span.offset);
+                            if sender.send(span).is_err() {
+                                return;
+                            }
+                            span = Span {
+                                offset: end,
+                                len: 0,
+                                lines: 0,
+                                terminated: true,
+                            };
+                        }
+                    }
+                }
+                offset = offset.saturating_add(read as u64);
+                last_byte = buffer.get(read.saturating_sub(1)).copied().unwrap_or(b'\n');
+            }
+            span.len = offset.saturating_sub(span.offset);
+            if last_byte != b'\n' {
+                span.lines = span.lines.saturating_add(1);
+                span.terminated = false;
+            }
+            if span.lines > 0 {
+                sender.send(span).ok();
+            }
+        });
+        Ok(Self {
+            source: Some(source),
+            index: Some(receiver),
+            encoding,
+            ..Self::default()
+        })
+    }
+    pub fn is_mapped(&self) -> bool {
+        self.source.is_some()
+    }
+    pub fn is_indexing(&self) -> bool {
+        self.index.is_some()
+    }
+    // Picks up the chunks the indexing thread has found so far; returns
+    // whether the rope grew.
+    pub fn poll_index(&mut self) -> bool {
+        let mut grown = false;
+        while let Some(receiver) = &self.index {
+            match receiver.try_recv() {
+                Ok(span) => {
+                    self.push_span(span);
+                    grown = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.index = None,
+            }
+        }
+        grown
+    }
+    fn finish_index(&mut self) {
+        if let Some(receiver) = self.index.take() {
+            for span in receiver {
+                self.push_span(span);
+            }
+        }
+    }
+    fn push_span(&mut self, span: Span) {
+        self.starts.push(self.len);
+        self.len = self.len.saturating_add(span.lines);
+        self.chunks.push(Chunk::Mapped(span));
+    }
+    fn read_span(&self, span: Span) -> Result<Vec<Row>, io::Error> {
+        let mut bytes = Vec::new();
+        if let Some(mut source) = self.source.as_ref() {
+            source.seek(SeekFrom::Start(span.offset))?;
+            source.take(span.len).read_to_end(&mut bytes)?;
+        }
+        let text = self.encoding.decode(&bytes);
+        let mut rows: Vec<Row> = split_lines(&text, false)
+            .into_iter()
+            .map(|(line, ending)| Row::with_ending(line, ending))
+            .collect();
+        rows.resize_with(span.lines, Row::default);
+        Ok(rows)
+    }
+    // Reads the mapped chunks covering rows `start..end` and drops unedited
+    // chunks far away from them.
+    pub fn load(&mut self, start: usize, end: usize) -> Result<(), io::Error> {
+        if !self.is_mapped() {
+            return Ok(());
+        }
+        let first = self.locate(start).0;
+        let last = self.locate(end.saturating_sub(1)).0;
+        for chunk in first..=last {
+            if let Some(Chunk::Mapped(span)) = self.chunks.get(chunk) {
+                let span = *span;
+                let rows = self.read_span(span)?;
+                if let Some(chunk) = self.chunks.get_mut(chunk) {
+                    *chunk = Chunk::Loaded {
+                        rows,
+                        span: Some(span),
+                    };
+                }
+            }
+        }
+        let keep = first.saturating_sub(LOADED_MARGIN)..=last.saturating_add(LOADED_MARGIN);
+        for (index, chunk) in self.chunks.iter_mut().enumerate() {
+            if let Chunk::Loaded {
+                span: Some(span), ..
+            } = chunk
+            {
+                if !keep.contains(&index) {
+                    *chunk = Chunk::Mapped(*span);
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn set_encoding(&mut self, encoding: TextEncoding) {
+        self.encoding = encoding;
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    // Returns the chunk holding row `index` and the row's offset inside it.
+    fn locate(&self, index: usize) -> (usize, usize) {
+        let chunk = self
+            .starts
+            .partition_point(|start| *start <= index)
+            .saturating_sub(1);
+        let start = self.starts.get(chunk).copied().unwrap_or(0);
+        (chunk, index.saturating_sub(start))
+    }
+    pub fn get(&self, index: usize) -> Option<&Row> {
+        if index >= self.len {
+            return None;
+        }
+        let (chunk, offset) = self.locate(index);
+        self.chunks.get(chunk)?.rows()?.get(offset)
+    }
+    // Mutable access that leaves the text alone, e.g. for highlighting.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Row> {
+        if index >= self.len {
+            return None;
+        }
+        let (chunk, offset) = self.locate(index);
+        self.chunks.get_mut(chunk)?.rows_mut()?.get_mut(offset)
+    }
+    // Mutable access for changing the text; the chunk is kept in memory from
+    // now on.
+    pub fn edit(&mut self, index: usize) -> Option<&mut Row> {
+        if index >= self.len {
+            return None;
+        }
+        let (chunk, offset) = self.locate(index);
+        if let Chunk::Loaded { rows, span } = self.chunks.get_mut(chunk)? {
+            *span = None;
+            rows.get_mut(offset)
+        } else {
+            None
+        }
+    }
+    pub fn insert(&mut self, index: usize, row: Row) {
+        if index > self.len {
+            return;
+        }
+        if self.chunks.is_empty() {
+            self.chunks.push(Chunk::Loaded {
+                rows: Vec::new(),
+                span: None,
+            });
+            self.starts.push(0);
+        }
+        let (chunk, offset) = if index == self.len {
+            let last = self.chunks.len().saturating_sub(1);
+            (last, self.chunks.get(last).map_or(0, Chunk::len))
+        } else {
+            self.locate(index)
+        };
+        if let Some(Chunk::Loaded { rows, span }) = self.chunks.get_mut(chunk) {
+            *span = None;
+            rows.insert(offset, row);
+            if rows.len() > CHUNK_SIZE.saturating_mul(2) {
+                let tail = rows.split_off(CHUNK_SIZE);
+                self.chunks.insert(
+                    chunk.saturating_add(1),
+                    Chunk::Loaded {
+                        rows: tail,
+                        span: None,
+                    },
+                );
+                self.starts.insert(chunk.saturating_add(1), 0);
+            }
+        } else {
+            return;
+        }
+        self.len = self.len.saturating_add(1);
+        self.update_starts(chunk);
+    }
+    pub fn remove(&mut self, index: usize) -> Option<Row> {
+        if index >= self.len {
+            return None;
+        }
+        let (chunk, offset) = self.locate(index);
+        let (row, is_empty) = if let Chunk::Loaded { rows, span } = self.chunks.get_mut(chunk)? {
+            *span = None;
+            (rows.remove(offset), rows.is_empty())
+        } else {
+            return None;
+        };
+        if is_empty {
+            self.chunks.remove(chunk);
+            self.starts.remove(chunk);
+        }
+        self.len = self.len.saturating_sub(1);
+        self.update_starts(chunk);
+        Some(row)
+    }
+    fn update_starts(&mut self, from: usize) {
+        let mut start = if from == 0 {
+            0
+        } else {
+            let previous = from.saturating_sub(1);
+            self.starts
+                .get(previous)
+                .copied()
+                .unwrap_or(0)
+                .saturating_add(self.chunks.get(previous).map_or(0, Chunk::len))
+        };
+        for (chunk_start, chunk) in self.starts.iter_mut().zip(&self.chunks).skip(from) {
+            *chunk_start = start;
+            start = start.saturating_add(chunk.len());
+        }
+    }
+    // Loaded rows before `until`, in order and with their index; rows of
+    // mapped chunks are skipped.
+    pub fn loaded_mut(&mut self, until: usize) -> impl Iterator<Item = (usize, &mut Row)> {
+        self.chunks
+            .iter_mut()
+            .zip(&self.starts)
+            .take_while(move |(_, start)| **start < until)
+            .filter_map(move |(chunk, start)| {
+                let rows = chunk.rows_mut()?;
+                Some(
+                    rows.iter_mut()
+                        .take(until.saturating_sub(*start))
+                        .enumerate()
+                        .map(move |(index, row)| (start.saturating_add(index), row)),
+                )
+            })
+            .flatten()
+    }
+    pub fn reader(&self) -> RowReader<'_> {
+        RowReader {
+            rope: self,
+            chunk: None,
+        }
+    }
+    // Writes every row followed by its own line ending, or `ending` if it has
+    // none. The last row only gets one with `final_newline`. Mapped chunks are
+    // copied from the source file as they are.
+    pub fn write_to<W: Write>(
+        &self,
+        writer: &mut W,
+        ending: LineEnding,
+        final_newline: bool,
+    ) -> Result<(), io::Error> {
+        writer.write_all(self.encoding.bom())?;
+        let last = self.chunks.len().saturating_sub(1);
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            let final_newline = index != last || final_newline;
+            self.write_chunk(chunk, writer, ending, final_newline)?;
+        }
+        Ok(())
+    }
+    // Returns whether the chunk ended with a line ending.
+    fn write_chunk<W: Write>(
+        &self,
+        chunk: &Chunk,
+        writer: &mut W,
+        ending: LineEnding,
+        final_newline: bool,
+    ) -> Result<bool, io::Error> {
+        match chunk {
+            Chunk::Loaded { rows, .. } => {
+                let mut bytes = Vec::new();
+                for (index, row) in rows.iter().enumerate() {
+                    bytes.clear();
+                    self.encoding.encode(row.as_str(), &mut bytes)?;
+                    if final_newline || index.saturating_add(1) < rows.len() {
+                        let row_ending = row.ending().unwrap_or(ending);
+                        self.encoding.encode(row_ending.as_str(), &mut bytes)?;
+                    }
+                    writer.write_all(&bytes)?;
+                }
+                Ok(final_newline || rows.is_empty())
+            }
+            Chunk::Mapped(span) => {
+                if let Some(mut source) = self.source.as_ref() {
+                    source.seek(SeekFrom::Start(span.offset))?;
+                    io::copy(&mut source.take(span.len), writer)?;
+                }
+                if !span.terminated && final_newline {
+                    let mut bytes = Vec::new();
+                    self.encoding.encode(ending.as_str(), &mut bytes)?;
+                    writer.write_all(&bytes)?;
+                    return Ok(true);
+                }
+                Ok(span.terminated)
+            }
+        }
+    }
+    // Saves a mapped rope. The original file still backs the mapped chunks,
+    // so the new contents go to a temporary file that then replaces it, and
+    // the rope is remapped onto the result. Returns the hash of what was
+    // written, as it cannot be read back cheaply.
+    pub fn save_mapped(
+        &mut self,
+        file_name: &str,
+        ending: LineEnding,
+        final_newline: bool,
+        backup: bool,
+    ) -> Result<u64, io::Error> {
+        self.finish_index();
+        let (spans, hash) = write_atomically(file_name, backup, |writer| {
+            writer.write_all(self.encoding.bom())?;
+            let mut hash = extend_hash(EMPTY_HASH, self.encoding.bom());
+            let mut spans = Vec::new();
+            let mut offset = self.encoding.bom().len() as u64;
+            let last = self.chunks.len().saturating_sub(1);
+            for (index, chunk) in self.chunks.iter().enumerate() {
+                let mut counter = CountingWriter {
+                    inner: &mut *writer,
+                    count: 0,
+                    hash,
+                };
+                let final_newline = index != last || final_newline;
+                let terminated = self.write_chunk(chunk, &mut counter, ending, final_newline)?;
+                spans.push(Span {
+                    offset,
+                    len: counter.count,
+                    lines: chunk.len(),
+                    terminated,
+                });
+                offset = offset.saturating_add(counter.count);
+                hash = counter.hash;
+            }
+            Ok((spans, hash))
+        })?;
+        self.source = Some(File::open(file_name)?);
+        for (chunk, new_span) in self.chunks.iter_mut().zip(spans) {
+            match chunk {
+                Chunk::Loaded { span, .. } => *span = Some(new_span),
+                Chunk::Mapped(span) => *span = new_span,
+            }
+        }
+        Ok(hash)
+    }
+}
+
+// Counts and hashes the bytes written through it.
+struct CountingWriter<'a, W: Write> {
+    inner: &'a mut W,
+    count: u64,
+    hash: u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let written = self.inner.write(buf)?;
+        self.count = self.count.saturating_add(written as u64);
+        self.hash = extend_hash(self.hash, buf.get(..written).unwrap_or_default());
+        Ok(written)
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+// Reads rows of a rope without loading mapped chunks into it; the last chunk
+// read from the file is kept so walking through rows reads each chunk once.
+pub struct RowReader<'a> {
+    rope: &'a Rope,
+    chunk: Option<(usize, Vec<Row>)>,
+}
+
+impl RowReader<'_> {
+    pub fn get(&mut self, index: usize) -> Option<&Row> {
+        if index >= self.rope.len {
+            return None;
+        }
+        let (chunk, offset) = self.rope.locate(index);
+        match self.rope.chunks.get(chunk)? {
+            Chunk::Loaded { rows, .. } => rows.get(offset),
+            Chunk::Mapped(span) => {
+                if self.chunk.as_ref().map(|(cached, _)| *cached) != Some(chunk) {
+                    self.chunk = Some((chunk, self.rope.read_span(*span).ok()?));
+                }
+                self.chunk.as_ref()?.1.get(offset)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_insert_remove_across_chunks() {
+        let mut rope = Rope::from(
+            (0..CHUNK_SIZE * 3)
+                .map(|index| Row::from(index.to_string().as_str()))
+                .collect::<Vec<Row>>(),
+        );
+        for _ in 0..CHUNK_SIZE * 2 {
+            rope.insert(CHUNK_SIZE + 1, Row::from("new"));
+        }
+        assert_eq!(rope.len(), CHUNK_SIZE * 5);
+        assert_eq!(rope.get(CHUNK_SIZE).map(Row::as_bytes), Some("512".as_bytes()));
+        assert_eq!(rope.get(CHUNK_SIZE * 3 + 1).map(Row::as_bytes), Some("513".as_bytes()));
+        for _ in 0..CHUNK_SIZE * 2 {
+            assert!(rope.remove(CHUNK_SIZE + 1).is_some());
+        }
+        let mut rows = Vec::new();
+        rope.write_to(&mut rows, LineEnding::Lf, true).unwrap();
+        assert_eq!(rows.iter().filter(|byte| **byte == b'\n').count(), CHUNK_SIZE * 3);
+        assert!(rows.ends_with(b"1534\n1535\n"));
+        assert!(rope.remove(CHUNK_SIZE * 3).is_none());
+    }
+
+    #[test]
+    fn test_mapped_file() {
+        let path = std::env::temp_dir().join(format!("hecto-map-{}.txt", std::process::id()));
+        let file_name = path.to_string_lossy().to_string();
+        let contents: String = (0..CHUNK_SIZE * 20).map(|line| format!("line {}\n", line)).collect();
+        fs::write(&path, format!("{}last", contents)).unwrap();
+        let mut rope = Rope::map(&file_name, TextEncoding::default()).unwrap();
+        rope.finish_index();
+        assert_eq!(rope.len(), CHUNK_SIZE * 20 + 1);
+        assert!(rope.get(CHUNK_SIZE * 10).is_none());
+        assert_eq!(
+            rope.reader().get(CHUNK_SIZE * 10).map(Row::as_bytes),
+            Some("line 5120".as_bytes())
+        );
+
+        rope.load(CHUNK_SIZE * 10, CHUNK_SIZE * 10 + 1).unwrap();
+        if let Some(row) = rope.edit(CHUNK_SIZE * 10) {
+            row.insert_str(0, "edited ");
+        }
+        rope.load(0, 1).unwrap();
+        assert_eq!(rope.get(0).map(Row::as_bytes), Some("line 0".as_bytes()));
+        assert!(rope.get(CHUNK_SIZE * 10).is_some());
+        assert!(rope.get(CHUNK_SIZE * 11).is_none());
+
+        let hash = rope.save_mapped(&file_name, LineEnding::Lf, false, false).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert_eq!(hash, extend_hash(EMPTY_HASH, saved.as_bytes()));
+        assert!(saved.contains("\nedited line 5120\nline 5121\n"));
+        assert!(saved.ends_with("line 10239\nlast"));
+        rope.load(CHUNK_SIZE * 20, CHUNK_SIZE * 20 + 1).unwrap();
+        assert_eq!(rope.get(CHUNK_SIZE * 20).map(Row::as_bytes), Some("last".as_bytes()));
+        fs::remove_file(path).ok();
+    }
+}
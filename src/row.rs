@@ -1,20 +1,30 @@
 use crate::highlighting;
 use crate::HighlightingOptions;
+use crate::LineEnding;
+use crate::SearchQuery;
 use crate::SearchDirection;
+use crate::width;
 use std::cmp;
+use std::ops::Range;
 use unicode_segmentation::UnicodeSegmentation;
 use crossterm::{
     style::SetForegroundColor,
     style::Color
 };
-use unicode_width::UnicodeWidthChar;
+
+const SPLIT_LEFT: &str = "<";
+const SPLIT_RIGHT: &str = ">";
 
 #[derive(Default, Clone)]
 pub struct Row {
     string: String,
     highlighting: Vec<highlighting::Type>,
     pub is_highlighted: bool,
-    len: usize,
+    // Byte offset of every grapheme, so edits can find their position without
+    // re-segmenting the whole line.
+    boundaries: Vec<usize>,
+    // The terminator read from the file; `None` uses the document's.
+    ending: Option<LineEnding>,
 }
 
 impl From<&str> for Row {
@@ -23,171 +33,276 @@ impl From<&str> for Row {
             string: String::from(slice),
             highlighting: Vec::new(),
             is_highlighted: false,
-            len: slice.graphemes(true).count(),
+            boundaries: slice.grapheme_indices(true).map(|(index, _)| index).collect(),
+            ending: None,
         }
     }
 }
 
 impl Row {
+    // The part of the row between screen columns `start` and `end`. A wide
+    // character cut by either edge shows as a placeholder in the columns
+    // that are left of it.
     pub fn render(&self, start: usize, end: usize) -> String {
-        let end = cmp::min(end, self.string.len());
-        let start = cmp::min(start, end);
         let mut result = String::new();
         let mut current_highlighting = &highlighting::Type::None;
-        #[allow(clippy::integer_arithmetic)]
-        for (index, grapheme) in self.string[..]
-            .graphemes(true)
-            .enumerate()
-            .skip(start)
-            .take(end - start)
-        {
-            if let Some(c) = grapheme.chars().next() {
-                let highlighting_type = self
-                    .highlighting
-                    .get(index)
-                    .unwrap_or(&highlighting::Type::None);
-                if highlighting_type != current_highlighting {
-                    current_highlighting = highlighting_type;
-                    result.push_str(format!("{}", SetForegroundColor(highlighting_type.to_color())).as_str());
-                }
-                if c == '\t' {
-                    result.push_str(" ");
-                } else {
-                    result.push(c);
-                }
+        let mut column = 0_usize;
+        for (index, grapheme) in self.grapheme_iter().enumerate() {
+            let next = width::advance(column, grapheme);
+            if next <= start {
+                column = next;
+                continue;
+            }
+            if column >= end {
+                break;
+            }
+            let highlighting_type = self
+                .highlighting
+                .get(index)
+                .unwrap_or(&highlighting::Type::None);
+            if highlighting_type != current_highlighting {
+                current_highlighting = highlighting_type;
+                result.push_str(format!("{}", SetForegroundColor(highlighting_type.to_color())).as_str());
+            }
+            if grapheme == "\t" {
+                let visible = next.min(end).saturating_sub(column.max(start));
+                result.push_str(&" ".repeat(visible));
+            } else if column < start {
+                result.push_str(&SPLIT_LEFT.repeat(next.saturating_sub(start)));
+            } else if next > end {
+                result.push_str(&SPLIT_RIGHT.repeat(end.saturating_sub(column)));
+            } else if grapheme.chars().any(char::is_control) {
+                result.push('?');
+            } else if width::is_zero_width(grapheme) {
+                // A mark with nothing to combine with goes on a space.
+                result.push(' ');
+                result.push_str(grapheme);
+            } else {
+                result.push_str(grapheme);
             }
+            column = next;
         }
         let reset_color = format!("{}", SetForegroundColor(Color::Reset));
         result.push_str(&reset_color);
         result
     }
+    pub fn with_ending(slice: &str, ending: Option<LineEnding>) -> Self {
+        let mut row = Self::from(slice);
+        row.ending = ending;
+        row
+    }
+    pub fn ending(&self) -> Option<LineEnding> {
+        self.ending
+    }
+    pub fn set_ending(&mut self, ending: Option<LineEnding>) {
+        self.ending = ending;
+    }
     pub fn len(&self) -> usize {
-        self.len
+        self.boundaries.len()
     }
     pub fn is_empty(&self) -> bool {
-        self.len == 0
+        self.boundaries.is_empty()
     }
     pub fn insert(&mut self, at: usize, c: char) {
-        if at >= self.len() {
-            self.string.push(c);
-            self.len += 1;
-            return;
-        }
-        let mut result: String = String::new();
-        let mut length = 0;
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            length += 1;
-            if index == at {
-                length += 1;
-                result.push(c);
-            }
-            result.push_str(grapheme);
-        }
-        self.len = length;
-        self.string = result;
+        let mut buffer = [0; 4];
+        self.insert_str(at, c.encode_utf8(&mut buffer));
+    }
+    pub fn insert_str(&mut self, at: usize, string: &str) {
+        let at = cmp::min(at, self.len());
+        let byte_index = self.byte_index(at);
+        self.string.insert_str(byte_index, string);
+        self.reindex(at, byte_index, byte_index.saturating_add(string.len()));
     }
     pub fn delete(&mut self, at: usize) {
         if at >= self.len() {
             return;
         }
-        let mut result: String = String::new();
-        let mut length = 0;
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            if index != at {
-                length += 1;
-                result.push_str(grapheme);
+        self.remove(at, 1);
+    }
+    pub fn remove(&mut self, at: usize, count: usize) -> String {
+        let at = cmp::min(at, self.len());
+        let start = self.byte_index(at);
+        let end = self.byte_index(at.saturating_add(count));
+        let removed = self.string.drain(start..end).collect();
+        self.reindex(at, end, start);
+        removed
+    }
+    // Puts `string` in place of `count` graphemes from `at` in one step.
+    pub fn replace(&mut self, at: usize, count: usize, string: &str) {
+        self.splice(at, count, string);
+    }
+    // Like `replace`, but returns the graphemes that changed as their index,
+    // old text and new text. The text put in can join or split the graphemes
+    // around it, so these can reach past `at` and `count`.
+    #[allow(clippy::integer_arithmetic)]
+    pub fn splice(&mut self, at: usize, count: usize, string: &str) -> (usize, String, String) {
+        let at = cmp::min(at, self.len());
+        let start = self.byte_index(at);
+        let end = self.byte_index(at.saturating_add(count));
+        let removed = self.string.get(start..end).unwrap_or_default().to_string();
+        self.string.replace_range(start..end, string);
+        let (from, len) = self.reindex(at, end, start + string.len());
+        let span = self.byte_index(from)..self.byte_index(from + len);
+        let old = [
+            self.string.get(span.start..start).unwrap_or_default(),
+            &removed,
+            self.string.get(start + string.len()..span.end).unwrap_or_default(),
+        ]
+        .concat();
+        let new = self.string.get(span).unwrap_or_default();
+        let old: Vec<&str> = old.graphemes(true).collect();
+        let new: Vec<&str> = new.graphemes(true).collect();
+        let prefix = old.iter().zip(&new).take_while(|(old, new)| old == new).count();
+        let suffix = old
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .take_while(|(old, new)| old == new)
+            .count()
+            .min(cmp::min(old.len(), new.len()) - prefix);
+        let changed = |graphemes: &[&str]| -> String {
+            graphemes
+                .iter()
+                .skip(prefix)
+                .take(graphemes.len() - prefix - suffix)
+                .copied()
+                .collect()
+        };
+        (from + prefix, changed(&old), changed(&new))
+    }
+    pub fn graphemes(&self, at: usize, count: usize) -> String {
+        let start = self.byte_index(at);
+        let end = self.byte_index(at.saturating_add(count));
+        #[allow(clippy::indexing_slicing)]
+        self.string[start..end].to_string()
+    }
+    fn byte_index(&self, at: usize) -> usize {
+        self.boundaries
+            .get(at)
+            .copied()
+            .unwrap_or(self.string.len())
+    }
+    // Updates the grapheme index after the bytes that used to end at `old_end`
+    // now end at `new_end`. Segmentation restarts one grapheme before the edit
+    // (a combining mark can join its predecessor) and stops as soon as it meets
+    // an unchanged boundary again. Returns the first grapheme segmented again
+    // and how many there are now.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn reindex(&mut self, at: usize, old_end: usize, new_end: usize) -> (usize, usize) {
+        let from = at.saturating_sub(1);
+        let start = if from < at { self.byte_index(from) } else { 0 };
+        let mut old = self.boundaries.partition_point(|boundary| *boundary < old_end);
+        for boundary in &mut self.boundaries[old..] {
+            *boundary = *boundary - old_end + new_end;
+        }
+        let mut segmented = Vec::new();
+        let mut end = self.boundaries.len();
+        for (index, _) in self.string[start..].grapheme_indices(true) {
+            let boundary = start + index;
+            while self.boundaries.get(old).map_or(false, |old| *old < boundary) {
+                old += 1;
+            }
+            if boundary >= new_end && self.boundaries.get(old) == Some(&boundary) {
+                end = old;
+                break;
             }
+            segmented.push(boundary);
         }
-        self.len = length;
-        self.string = result;
+        let len = segmented.len();
+        self.boundaries.splice(from..end, segmented);
+        (from, len)
+    }
+    // How many graphemes `self` and `other` start with in common.
+    pub fn common_prefix(&self, other: &Self) -> usize {
+        (0..cmp::min(self.len(), other.len()))
+            .take_while(|index| self.grapheme(*index) == other.grapheme(*index))
+            .count()
+    }
+    // How many graphemes `self` and `other` end with in common.
+    #[allow(clippy::integer_arithmetic)]
+    pub fn common_suffix(&self, other: &Self) -> usize {
+        (1..=cmp::min(self.len(), other.len()))
+            .take_while(|back| self.grapheme(self.len() - back) == other.grapheme(other.len() - back))
+            .count()
+    }
+    // The graphemes of the row, read off the index.
+    fn grapheme_iter(&self) -> impl Iterator<Item = &str> {
+        (0..self.len()).map(move |index| self.grapheme(index))
+    }
+    fn grapheme(&self, index: usize) -> &str {
+        let start = self.byte_index(index);
+        let end = self.byte_index(index.saturating_add(1));
+        self.string.get(start..end).unwrap_or_default()
     }
     pub fn append(&mut self, new: &Self) {
-        self.string = format!("{}{}", self.string, new.string);
-        self.len += new.len;
+        let offset = self.string.len();
+        self.string.push_str(&new.string);
+        let at = self.len();
+        self.boundaries
+            .extend(new.boundaries.iter().map(|boundary| boundary.saturating_add(offset)));
+        self.reindex(at, offset, offset);
+        self.ending = new.ending;
     }
     pub fn split(&mut self, at: usize) -> Self {
-        let mut row: String = String::new();
-        let mut length = 0;
-        let mut splitted_row: String = String::new();
-        let mut splitted_length = 0;
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            if index < at {
-                length += 1;
-                row.push_str(grapheme);
-            } else {
-                splitted_length += 1;
-                splitted_row.push_str(grapheme);
-            }
-        }
-
-        self.string = row;
-        self.len = length;
+        let at = cmp::min(at, self.len());
+        let byte_index = self.byte_index(at);
+        let splitted_row = self.string.split_off(byte_index);
+        let splitted_boundaries = self
+            .boundaries
+            .split_off(at)
+            .into_iter()
+            .map(|boundary| boundary.saturating_sub(byte_index))
+            .collect();
         self.is_highlighted = false;
         Self {
             string: splitted_row,
-            len: splitted_length,
             is_highlighted: false,
             highlighting: Vec::new(),
+            boundaries: splitted_boundaries,
+            ending: self.ending,
         }
     }
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
     pub fn as_bytes(&self) -> &[u8] {
         self.string.as_bytes()
     }
-    pub fn find(&self, query: &str, at: usize, direction: SearchDirection) -> Option<usize> {
-        if at > self.len || query.is_empty() {
+    pub fn find(&self, query: &SearchQuery, at: usize, direction: SearchDirection) -> Option<usize> {
+        if at > self.len() {
             return None;
         }
-        let start = if direction == SearchDirection::Forward {
-            at
+        let at = self.byte_index(at);
+        let found = if direction == SearchDirection::Forward {
+            query.find_at(&self.string, at)
         } else {
-            0
-        };
-        let end = if direction == SearchDirection::Forward {
-            self.len
-        } else {
-            at
-        };
-        #[allow(clippy::integer_arithmetic)]
-        let substring: String = self.string[..]
-            .graphemes(true)
-            .skip(start)
-            .take(end - start)
-            .collect();
-        let matching_byte_index = if direction == SearchDirection::Forward {
-            substring.find(query)
-        } else {
-            substring.rfind(query)
-        };
-        if let Some(matching_byte_index) = matching_byte_index {
-            for (grapheme_index, (byte_index, _)) in
-                substring[..].grapheme_indices(true).enumerate()
-            {
-                if matching_byte_index == byte_index {
-                    #[allow(clippy::integer_arithmetic)]
-                    return Some(start + grapheme_index);
-                }
-            }
-        }
-        None
+            query.matches(&self.string).take_while(|found| found.end <= at).last()
+        }?;
+        Some(self.grapheme_at(found.start))
+    }
+    // The grapheme that contains byte `index`.
+    fn grapheme_at(&self, index: usize) -> usize {
+        self.boundaries
+            .partition_point(|boundary| *boundary <= index)
+            .saturating_sub(1)
+    }
+    fn graphemes_before(&self, index: usize) -> usize {
+        self.boundaries.partition_point(|boundary| *boundary < index)
     }
 
-    fn highlight_match(&mut self, word: &Option<String>) {
-        if let Some(word) = word {
-            if word.is_empty() {
-                return;
-            }
-            let mut index = 0;
-            while let Some(search_match) = self.find(word, index, SearchDirection::Forward) {
-                if let Some(next_index) = search_match.checked_add(word[..].graphemes(true).count())
-                {
-                    #[allow(clippy::indexing_slicing)]
-                    for i in search_match..next_index {
-                        self.highlighting[i] = highlighting::Type::Match;
-                    }
-                    index = next_index;
-                } else {
-                    break;
-                }
+    // Marks the parts of matches that fall on this row, given in bytes; a
+    // match that goes on to the next row runs to the end of this one.
+    fn highlight_match(&mut self, matches: &[(Range<usize>, highlighting::Type)]) {
+        for (bytes, hl_type) in matches {
+            self.highlight_range(bytes, *hl_type);
+        }
+    }
+    fn highlight_range(&mut self, bytes: &Range<usize>, hl_type: highlighting::Type) {
+        let start = self.grapheme_at(bytes.start);
+        let end = self.graphemes_before(bytes.end);
+        for index in start..end {
+            if let Some(highlighting) = self.highlighting.get_mut(index) {
+                *highlighting = hl_type;
             }
         }
     }
@@ -409,11 +524,14 @@ impl Row {
     pub fn highlight(
         &mut self,
         opts: &HighlightingOptions,
-        word: &Option<String>,
+        matches: Option<&[(Range<usize>, highlighting::Type)]>,
         start_with_comment: bool,
     ) -> bool {
         let chars: Vec<char> = self.string.chars().collect();
-        if self.is_highlighted && word.is_none() {
+        // A row still coloured by a search that has since been cleared needs
+        // highlighting again.
+        let has_matches = self.highlighting.iter().any(|hl_type| hl_type.is_match());
+        if self.is_highlighted && matches.is_none() && !has_matches {
             if let Some(hl_type) = self.highlighting.last() {
                 if *hl_type == highlighting::Type::MultilineComment
                     && self.string.len() > 1
@@ -456,44 +574,36 @@ impl Row {
             self.highlighting.push(highlighting::Type::None);
             index += 1;
         }
-        self.highlight_match(word);
+        if let Some(matches) = matches {
+            self.highlight_match(matches);
+        }
         if in_ml_comment && &self.string[self.string.len().saturating_sub(2)..] != "*/" {
             return true;
         }
         self.is_highlighted = true;
         false
     }
-    pub fn get_char_width(&self, c: char) -> usize {
-        if c.is_ascii() {
-            1
-        } else {
-            2  // CJK characters
-        }
-    }
-    pub fn get_char(&self, at: usize) -> Option<char> {
-        self.string.chars().nth(at)
-    }
+    // How many columns the graphemes before `up_to` take on screen.
     pub fn get_width_to(&self, up_to: usize) -> usize {
-        self.string[..]
-            .graphemes(true)
-            .take(up_to)
-            .map(|g| {
-                if let Some(c) = g.chars().next() {
-                    self.get_char_width(c)
-                } else {
-                    1
-                }
-            })
-            .sum()
+        self.grapheme_iter().take(up_to).fold(0, width::advance)
     }
+    // How many columns the grapheme at `index` takes, or 1 past the end of
+    // the row, where the cursor can still be.
+    pub fn get_width_at(&self, index: usize) -> usize {
+        if index >= self.len() {
+            return 1;
+        }
+        let column = self.get_width_to(index);
+        width::advance(column, self.grapheme(index)).saturating_sub(column)
+    }
+    // The grapheme that covers screen column `visual_pos`, or the length of
+    // the row past its end.
     pub fn get_char_index(&self, visual_pos: usize) -> usize {
-        let mut current_width = 0;
-        for (i, grapheme) in self.string[..].graphemes(true).enumerate() {
-            if let Some(c) = grapheme.chars().next() {
-                current_width += self.get_char_width(c);
-                if current_width > visual_pos {
-                    return i;
-                }
+        let mut current_width = 0_usize;
+        for (index, grapheme) in self.grapheme_iter().enumerate() {
+            current_width = width::advance(current_width, grapheme);
+            if current_width > visual_pos {
+                return index;
             }
         }
         self.len()
@@ -507,6 +617,12 @@ fn is_separator(c: char) -> bool {
 #[cfg(test)]
 mod test_super {
     use super::*;
+    use crate::search::SearchOptions;
+    use crate::width::DEFAULT_TAB_WIDTH;
+
+    fn query(text: &str) -> SearchQuery {
+        SearchQuery::new(text, SearchOptions::default()).unwrap()
+    }
 
     #[test]
     fn test_highlight_find() {
@@ -522,7 +638,11 @@ mod test_super {
             highlighting::Type::None,
             highlighting::Type::None,
         ];
-        row.highlight_match(&Some("t".to_string()));
+        row.highlight_match(&[
+            (1..2, highlighting::Type::Match),
+            (4..6, highlighting::Type::Match),
+            (8..9, highlighting::Type::Match),
+        ]);
         assert_eq!(
             vec![
                 highlighting::Type::Number,
@@ -539,11 +659,71 @@ mod test_super {
         )
     }
 
+    #[test]
+    fn test_highlight_clears_matches() {
+        let mut row = Row::from("test");
+        let opts = HighlightingOptions::default();
+        row.highlight(&opts, Some(&[(1..3, highlighting::Type::CurrentMatch)]), false);
+        assert_eq!(row.highlighting.get(1), Some(&highlighting::Type::CurrentMatch));
+        row.highlight(&opts, None, false);
+        assert!(!row.highlighting.iter().any(|hl_type| hl_type.is_match()));
+    }
+
     #[test]
     fn test_find() {
         let row = Row::from("1testtest");
-        assert_eq!(row.find("t", 0, SearchDirection::Forward), Some(1));
-        assert_eq!(row.find("t", 2, SearchDirection::Forward), Some(4));
-        assert_eq!(row.find("t", 5, SearchDirection::Forward), Some(5));
+        assert_eq!(row.find(&query("t"), 0, SearchDirection::Forward), Some(1));
+        assert_eq!(row.find(&query("t"), 2, SearchDirection::Forward), Some(4));
+        assert_eq!(row.find(&query("t"), 5, SearchDirection::Forward), Some(5));
+        assert_eq!(row.find(&query("t"), 5, SearchDirection::Backward), Some(4));
+        let row = Row::from("中文e\u{301}中");
+        assert_eq!(row.find(&query("中"), 1, SearchDirection::Forward), Some(3));
+    }
+
+    #[test]
+    fn test_width() {
+        let row = Row::from("e\u{301}中👍\u{1f3fd}─x");
+        assert_eq!(row.get_width_to(2), 3);
+        assert_eq!(row.get_width_to(row.len()), 7);
+        assert_eq!(row.get_char_index(2), 1);
+        assert_eq!(row.get_char_index(3), 2);
+        assert_eq!(row.get_char_index(6), 4);
+        assert_eq!(row.get_char_index(9), 5);
+        let row = Row::from("\tx\t");
+        assert_eq!(row.get_width_to(1), DEFAULT_TAB_WIDTH);
+        assert_eq!(row.get_width_at(2), DEFAULT_TAB_WIDTH - 1);
+        assert_eq!(row.get_char_index(DEFAULT_TAB_WIDTH - 1), 0);
+    }
+
+    #[test]
+    fn test_render() {
+        let plain = |row: &Row, start: usize, end: usize| {
+            let reset = format!("{}", SetForegroundColor(Color::Reset));
+            let none = format!("{}", SetForegroundColor(highlighting::Type::None.to_color()));
+            row.render(start, end).replace(&reset, "").replace(&none, "")
+        };
+        let row = Row::from("a中文b\te\u{301}");
+        assert_eq!(plain(&row, 0, 80), "a中文b  e\u{301}");
+        assert_eq!(plain(&row, 1, 5), "中文");
+        assert_eq!(plain(&row, 2, 4), "<>");
+        assert_eq!(plain(&row, 3, 7), "文b ");
+        assert_eq!(plain(&row, 7, 80), " e\u{301}");
+        assert_eq!(plain(&Row::from("\u{301}\u{1}x"), 0, 80), " \u{301}?x");
+    }
+
+    #[test]
+    fn test_edit_keeps_grapheme_index() {
+        let mut row = Row::from("中文e");
+        row.insert(3, '\u{301}');
+        assert_eq!(row.len(), 3);
+        row.insert(1, 'x');
+        assert_eq!(row.graphemes(1, 3), "x文e\u{301}");
+        assert_eq!(row.remove(0, 2), "中x");
+        let tail = row.split(1);
+        assert_eq!((row.len(), tail.len()), (1, 1));
+        row.append(&Row::from("\u{301}"));
+        row.append(&tail);
+        assert_eq!(row.len(), 2);
+        assert_eq!(row.graphemes(0, 2), "文\u{301}e\u{301}");
     }
 }
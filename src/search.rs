@@ -0,0 +1,173 @@
+use regex::{Regex, RegexBuilder};
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Default, PartialEq, Clone, Copy)]
+pub struct SearchOptions {
+    pub regex: bool,
+    pub ignore_case: bool,
+    pub whole_word: bool,
+}
+
+// Lists the active options, as shown before the search prompt.
+impl fmt::Display for SearchOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = [
+            (self.regex, "regex"),
+            (self.ignore_case, "ignore case"),
+            (self.whole_word, "whole word"),
+        ]
+        .iter()
+        .filter(|(active, _)| *active)
+        .map(|(_, name)| *name)
+        .collect();
+        if names.is_empty() {
+            Ok(())
+        } else {
+            write!(f, "[{}] ", names.join(", "))
+        }
+    }
+}
+
+// A search compiled with its options. Plain text is searched as an escaped
+// regular expression, so every mode goes through the same matcher.
+#[derive(Clone)]
+pub struct SearchQuery {
+    text: String,
+    regex: Regex,
+    // Replacements can refer to capture groups in regex mode.
+    options: SearchOptions,
+}
+
+// The same text searched with the same options finds the same matches.
+impl PartialEq for SearchQuery {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text && self.options == other.options
+    }
+}
+
+impl SearchQuery {
+    pub fn new(text: &str, options: SearchOptions) -> Result<Self, regex::Error> {
+        let pattern = if options.regex {
+            text.to_string()
+        } else {
+            regex::escape(text)
+        };
+        let pattern = if options.whole_word {
+            format!(r"\b(?:{})\b", pattern)
+        } else {
+            pattern
+        };
+        // Documents are searched as one text, so `^` and `$` still stand for
+        // the start and end of each line.
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(options.ignore_case)
+            .multi_line(true)
+            .build()?;
+        Ok(Self {
+            text: text.to_string(),
+            regex,
+            options,
+        })
+    }
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    // The first match in `line` starting at or after byte `from`. Empty
+    // matches, such as `a*` finds everywhere, cannot be shown and are skipped.
+    pub fn find_at(&self, line: &str, from: usize) -> Option<Range<usize>> {
+        let mut from = from;
+        while from <= line.len() {
+            let found = self.regex.find_at(line, from)?;
+            if !found.is_empty() {
+                return Some(found.range());
+            }
+            from = found.end().saturating_add(
+                line.get(found.end()..)
+                    .and_then(|rest| rest.chars().next())
+                    .map_or(1, char::len_utf8),
+            );
+        }
+        None
+    }
+    // The match that `find_at` gives, with what replaces it: `template` with
+    // `$1` or `${name}` standing for a capture group of a regular expression,
+    // or as it is for a plain search.
+    pub fn replacement(
+        &self,
+        line: &str,
+        from: usize,
+        template: &str,
+    ) -> Option<(Range<usize>, String)> {
+        let found = self.find_at(line, from)?;
+        let mut replacement = String::new();
+        if self.options.regex {
+            self.regex
+                .captures_at(line, found.start)?
+                .expand(template, &mut replacement);
+        } else {
+            replacement.push_str(template);
+        }
+        Some((found, replacement))
+    }
+    // Every match in `line` that is not empty, in order.
+    pub fn matches<'a>(&'a self, line: &'a str) -> impl Iterator<Item = Range<usize>> + 'a {
+        self.regex
+            .find_iter(line)
+            .filter(|found| !found.is_empty())
+            .map(|found| found.range())
+    }
+    // The matches in `line` with the ranges of the capture groups that took
+    // part in each.
+    pub fn captures(&self, line: &str) -> Vec<(Range<usize>, Vec<Range<usize>>)> {
+        self.regex
+            .captures_iter(line)
+            .filter_map(|captures| {
+                let whole = captures.get(0).filter(|found| !found.is_empty())?;
+                let groups = captures
+                    .iter()
+                    .skip(1)
+                    .flatten()
+                    .map(|group| group.range())
+                    .collect();
+                Some((whole.range(), groups))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn query(text: &str, regex: bool, ignore_case: bool, whole_word: bool) -> SearchQuery {
+        let options = SearchOptions {
+            regex,
+            ignore_case,
+            whole_word,
+        };
+        SearchQuery::new(text, options).unwrap()
+    }
+
+    #[test]
+    fn test_search_query() {
+        let line = "let x = a.b; let xs = A.B;";
+        assert_eq!(query("a.b", false, false, false).matches(line).count(), 1);
+        assert_eq!(query("a.b", false, true, false).matches(line).count(), 2);
+        assert_eq!(query("x", false, false, true).matches(line).collect::<Vec<_>>(), vec![4..5]);
+        assert_eq!(query("l.t", true, false, false).find_at(line, 1), Some(13..16));
+        assert_eq!(query("a*", true, false, false).find_at(line, 0), Some(8..9));
+        let captures = query(r"(\w+) = (\w)", true, false, false).captures(line);
+        assert_eq!(captures[0], (4..9, vec![4..5, 8..9]));
+        assert_eq!(query("中", false, false, false).find_at("文中", 0), Some(3..6));
+        let swap = query(r"(\w+) = (\w)", true, false, false);
+        assert_eq!(swap.replacement(line, 0, "$2 = $1"), Some((4..9, "a = x".to_string())));
+        let plain = query("a.b", false, false, false);
+        assert_eq!(plain.replacement(line, 0, "$1"), Some((8..11, "$1".to_string())));
+        assert!(SearchQuery::new("(", SearchOptions { regex: true, ..SearchOptions::default() }).is_err());
+        assert_eq!(
+            SearchOptions { regex: true, whole_word: true, ..SearchOptions::default() }.to_string(),
+            "[regex, whole word] "
+        );
+    }
+}
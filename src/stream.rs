@@ -0,0 +1,215 @@
+use crate::highlighting;
+use crate::Position;
+use crate::SearchDirection;
+use crate::SearchQuery;
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Rows joined by newlines, so that a search can match across them, with the
+// byte each row starts at. Positions are counted in graphemes, as in rows.
+pub struct TextStream {
+    text: String,
+    // The index of the first row in the document.
+    first: usize,
+    starts: Vec<usize>,
+}
+
+impl TextStream {
+    pub fn new(first: usize) -> Self {
+        Self {
+            text: String::new(),
+            first,
+            starts: Vec::new(),
+        }
+    }
+    pub fn push_line(&mut self, line: &str) {
+        if !self.starts.is_empty() {
+            self.text.push('\n');
+        }
+        self.starts.push(self.text.len());
+        self.text.push_str(line);
+    }
+    // The first match at or after `at`, or the last one that ends before it.
+    pub fn find(
+        &self,
+        query: &SearchQuery,
+        at: &Position,
+        direction: SearchDirection,
+    ) -> Option<Range<Position>> {
+        let at = self.byte(at);
+        let found = if direction == SearchDirection::Forward {
+            query.find_at(&self.text, at)
+        } else {
+            query.matches(&self.text).take_while(|found| found.end <= at).last()
+        }?;
+        Some(self.position(found.start, false)..self.position(found.end, true))
+    }
+    // Where each match starts, as stepping through them with `find` visits
+    // them: a match may start inside the previous one.
+    pub fn matches(&self, query: &SearchQuery) -> Vec<Position> {
+        let mut starts = Vec::new();
+        let mut from = 0;
+        while let Some(found) = query.find_at(&self.text, from) {
+            starts.push(self.position(found.start, false));
+            from = found.start.saturating_add(
+                self.text
+                    .get(found.start..)
+                    .and_then(|rest| rest.graphemes(true).next())
+                    .map_or(1, str::len),
+            );
+            if from >= self.text.len() {
+                break;
+            }
+        }
+        starts
+    }
+    // The match that starts at `at`, with what replaces it.
+    pub fn replacement(
+        &self,
+        query: &SearchQuery,
+        at: &Position,
+        template: &str,
+    ) -> Option<(Range<Position>, String)> {
+        let at = self.byte(at);
+        let (found, replacement) = query.replacement(&self.text, at, template)?;
+        if found.start != at {
+            return None;
+        }
+        Some((
+            self.position(found.start, false)..self.position(found.end, true),
+            replacement,
+        ))
+    }
+    // The bytes of each row to mark as a match or a capture group; a match
+    // that spans rows is marked on all of them, and the `current` one is
+    // marked apart.
+    pub fn highlights(
+        &self,
+        query: &SearchQuery,
+        current: Option<&Range<Position>>,
+    ) -> Vec<Vec<(Range<usize>, highlighting::Type)>> {
+        let mut highlights = vec![Vec::new(); self.starts.len()];
+        let current = current.map(|range| self.byte(&range.start)..self.byte(&range.end));
+        for (found, groups) in query.captures(&self.text) {
+            let hl_type = if current.as_ref() == Some(&found) {
+                highlighting::Type::CurrentMatch
+            } else {
+                highlighting::Type::Match
+            };
+            let ranges = groups
+                .into_iter()
+                .map(|group| (group, highlighting::Type::Capture));
+            for (range, hl_type) in std::iter::once((found, hl_type)).chain(ranges) {
+                let first = self.row_at(range.start);
+                let last = self.row_at(range.end.saturating_sub(1));
+                for index in first..=last {
+                    let start = self.starts.get(index).copied().unwrap_or(0);
+                    let end = self.row_end(index);
+                    let clipped = range.start.max(start).saturating_sub(start)
+                        ..range.end.min(end).saturating_sub(start);
+                    if let Some(row) = highlights.get_mut(index) {
+                        if !clipped.is_empty() {
+                            row.push((clipped, hl_type));
+                        }
+                    }
+                }
+            }
+        }
+        highlights
+    }
+    // The byte that `at` is at. A column past the end of its row is the start
+    // of the next one, so that a search can go on from there.
+    fn byte(&self, at: &Position) -> usize {
+        let index = at.y.saturating_sub(self.first);
+        let start = match self.starts.get(index) {
+            Some(start) => *start,
+            None => return self.text.len(),
+        };
+        let end = self.row_end(index);
+        let row = self.text.get(start..end).unwrap_or_default();
+        match row.grapheme_indices(true).nth(at.x) {
+            Some((offset, _)) => start.saturating_add(offset),
+            None if at.x == row.graphemes(true).count() => end,
+            None => end.saturating_add(1).min(self.text.len()),
+        }
+    }
+    // The position of `byte`, rounded up to the next grapheme for the end of
+    // a match and down otherwise.
+    fn position(&self, byte: usize, round_up: bool) -> Position {
+        let index = self.row_at(byte);
+        let start = self.starts.get(index).copied().unwrap_or(0);
+        let offset = byte.saturating_sub(start);
+        let graphemes = self
+            .text
+            .get(start..self.row_end(index))
+            .unwrap_or_default()
+            .grapheme_indices(true);
+        let x = if round_up || offset >= self.row_end(index).saturating_sub(start) {
+            graphemes.take_while(|(start, _)| *start < offset).count()
+        } else {
+            graphemes
+                .take_while(|(start, _)| *start <= offset)
+                .count()
+                .saturating_sub(1)
+        };
+        Position {
+            x,
+            y: self.first.saturating_add(index),
+        }
+    }
+    fn row_at(&self, byte: usize) -> usize {
+        self.starts
+            .partition_point(|start| *start <= byte)
+            .saturating_sub(1)
+    }
+    fn row_end(&self, index: usize) -> usize {
+        self.starts
+            .get(index.saturating_add(1))
+            .map_or(self.text.len(), |next| next.saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::SearchOptions;
+
+    fn stream(lines: &[&str]) -> TextStream {
+        let mut stream = TextStream::new(0);
+        for line in lines {
+            stream.push_line(line);
+        }
+        stream
+    }
+
+    #[test]
+    fn test_text_stream() {
+        let stream = stream(&["fn 中文", "broken", "phrase"]);
+        let regex = SearchOptions {
+            regex: true,
+            ..SearchOptions::default()
+        };
+        let query = SearchQuery::new(r"文\s+broken", regex).unwrap();
+        let found = stream.find(&query, &Position::default(), SearchDirection::Forward).unwrap();
+        assert_eq!((found.start.x, found.start.y, found.end.x, found.end.y), (4, 0, 6, 1));
+        let query = SearchQuery::new(r"n$", regex).unwrap();
+        let starts: Vec<(usize, usize)> = stream.matches(&query).iter().map(|at| (at.x, at.y)).collect();
+        assert_eq!(starts, vec![(5, 1)]);
+        let query = SearchQuery::new(r"\n", regex).unwrap();
+        let found = stream.find(&query, &Position { x: 7, y: 0 }, SearchDirection::Forward).unwrap();
+        assert_eq!((found.start.y, found.end.y), (1, 2));
+        let query = SearchQuery::new(r"en\nph", regex).unwrap();
+        let highlights = stream.highlights(&query, None);
+        assert_eq!(highlights[1], vec![(4..6, highlighting::Type::Match)]);
+        assert_eq!(highlights[2], vec![(0..2, highlighting::Type::Match)]);
+        assert!(highlights[0].is_empty());
+        let current = Position { x: 4, y: 1 }..Position { x: 2, y: 2 };
+        let highlights = stream.highlights(&query, Some(&current));
+        assert_eq!(highlights[2], vec![(0..2, highlighting::Type::CurrentMatch)]);
+        let (found, replacement) = stream
+            .replacement(&query, &Position { x: 4, y: 1 }, " ")
+            .unwrap();
+        assert_eq!((found.end.x, found.end.y, replacement.as_str()), (2, 2, " "));
+        assert!(stream.replacement(&query, &Position { x: 3, y: 1 }, " ").is_none());
+    }
+}
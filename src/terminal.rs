@@ -1,4 +1,5 @@
 use crate::Position;
+use std::fs::File;
 use std::io::{self, stdout, Write};
 use crossterm::{
     cursor,
@@ -18,10 +19,15 @@ pub struct Size {
 
 pub struct Terminal {
     size: Size,
+    // Standard output as it was given to us, when it is not the terminal.
+    output: Option<File>,
+    // Whether raw mode and the alternate screen are on, to be undone on drop.
+    attached: bool,
 }
 
 impl Terminal {
     pub fn default() -> Result<Self, std::io::Error> {
+        let output = redirect_stdout()?;
         let (width, height) = terminal::size()?;
         execute!(stdout(), EnterAlternateScreen)?;
         terminal::enable_raw_mode()?;
@@ -30,9 +36,30 @@ impl Terminal {
                 width,
                 height: height.saturating_sub(2),
             },
+            output,
+            attached: true,
         })
     }
 
+    // A screen of the given size that nothing is drawn on, for tests.
+    #[cfg(test)]
+    pub fn detached(width: u16, height: u16) -> Self {
+        Self {
+            size: Size { width, height },
+            output: None,
+            attached: false,
+        }
+    }
+
+    // Whether the editor runs as a filter, with standard output redirected.
+    pub fn has_output(&self) -> bool {
+        self.output.is_some()
+    }
+
+    pub fn take_output(&mut self) -> Option<File> {
+        self.output.take()
+    }
+
     pub fn size(&self) -> &Size {
         &self.size
     }
@@ -55,6 +82,15 @@ impl Terminal {
         io::stdout().flush()
     }
 
+    pub fn poll_key(timeout: std::time::Duration) -> Result<Option<KeyEvent>, std::io::Error> {
+        if event::poll(timeout)? {
+            if let event::Event::Key(key) = event::read()? {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn read_key() -> Result<KeyEvent, std::io::Error> {
         loop {
             if event::poll(std::time::Duration::from_millis(50))? {
@@ -119,7 +155,40 @@ impl Terminal {
 
 impl Drop for Terminal {
     fn drop(&mut self) {
+        if !self.attached {
+            return;
+        }
         execute!(stdout(), LeaveAlternateScreen).unwrap();
         terminal::disable_raw_mode().unwrap();
     }
-}
\ No newline at end of file
+}
+// Keys are already read from /dev/tty when standard input is not a terminal;
+// this does the same for the screen when standard output is redirected, and
+// keeps the redirected output so the text can be written to it on quit.
+#[cfg(unix)]
+fn redirect_stdout() -> Result<Option<File>, io::Error> {
+    use std::io::IsTerminal;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    if stdout().is_terminal() {
+        return Ok(None);
+    }
+    let tty = std::fs::OpenOptions::new().write(true).open("/dev/tty")?;
+    // SAFETY: `saved` is a new descriptor owned by nothing else, and fd 1 is
+    // only swapped for another open descriptor.
+    unsafe {
+        let saved = libc::dup(libc::STDOUT_FILENO);
+        if saved < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let output = File::from_raw_fd(saved);
+        if libc::dup2(tty.as_raw_fd(), libc::STDOUT_FILENO) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(output))
+    }
+}
+
+#[cfg(not(unix))]
+fn redirect_stdout() -> Result<Option<File>, io::Error> {
+    Ok(None)
+}
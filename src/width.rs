@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Whether East Asian ambiguous-width characters, such as `±`, `○` or Greek
+// and Cyrillic letters, take two columns as they do in many CJK terminals.
+static AMBIGUOUS_WIDE: AtomicBool = AtomicBool::new(false);
+
+// How many columns apart tab stops are.
+static TAB_WIDTH: AtomicUsize = AtomicUsize::new(DEFAULT_TAB_WIDTH);
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
+pub fn set_ambiguous_wide(wide: bool) {
+    AMBIGUOUS_WIDE.store(wide, Ordering::Relaxed);
+}
+pub fn set_tab_width(width: usize) {
+    TAB_WIDTH.store(width.max(1), Ordering::Relaxed);
+}
+pub fn tab_width() -> usize {
+    TAB_WIDTH.load(Ordering::Relaxed)
+}
+
+// How many columns a grapheme cluster takes on screen. One that would take
+// none, like a tab or a combining mark with nothing to combine with, gets a
+// column so that the cursor can still stop on it.
+pub fn grapheme_width(grapheme: &str) -> usize {
+    width_of(grapheme, AMBIGUOUS_WIDE.load(Ordering::Relaxed))
+}
+fn width_of(grapheme: &str, ambiguous_wide: bool) -> usize {
+    let width = if ambiguous_wide {
+        grapheme.width_cjk()
+    } else {
+        grapheme.width()
+    };
+    width.max(1)
+}
+
+// The column after `grapheme` when it starts at `column`: a tab runs to the
+// next tab stop.
+#[allow(clippy::integer_arithmetic)]
+pub fn advance(column: usize, grapheme: &str) -> usize {
+    if grapheme == "\t" {
+        let tab_width = tab_width();
+        column + tab_width - column % tab_width
+    } else {
+        column.saturating_add(grapheme_width(grapheme))
+    }
+}
+
+// Whether the terminal would draw `grapheme` in no columns at all.
+pub fn is_zero_width(grapheme: &str) -> bool {
+    grapheme.width() == 0
+}
+
+pub fn text_width(text: &str) -> usize {
+    text.graphemes(true).map(grapheme_width).sum()
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_grapheme_width() {
+        assert_eq!(grapheme_width("a"), 1);
+        assert_eq!(grapheme_width("é"), 1);
+        assert_eq!(grapheme_width("e\u{301}"), 1);
+        assert_eq!(grapheme_width("中"), 2);
+        assert_eq!(grapheme_width("─"), 1);
+        assert_eq!(grapheme_width("\u{301}"), 1);
+        assert_eq!(grapheme_width("\t"), 1);
+        assert_eq!(grapheme_width("👍"), 2);
+        assert_eq!(grapheme_width("👨\u{200d}👩\u{200d}👧"), 2);
+        assert_eq!(grapheme_width("🇨🇳"), 2);
+        assert_eq!(text_width("a中e\u{301}"), 4);
+        assert_eq!(advance(0, "\t"), DEFAULT_TAB_WIDTH);
+        assert_eq!(advance(DEFAULT_TAB_WIDTH - 1, "\t"), DEFAULT_TAB_WIDTH);
+        assert_eq!(advance(1, "中"), 3);
+        assert_eq!(width_of("±", true), 2);
+        assert_eq!(width_of("±", false), 1);
+        assert_eq!(width_of("中", true), 2);
+    }
+}